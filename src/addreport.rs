@@ -0,0 +1,72 @@
+use std::fmt;
+
+/// What `add` did with a single path.
+#[derive(Debug)]
+pub enum AddOutcome {
+    /// The path was not tracked before.
+    Added(u64),
+    /// The path was tracked and its content or metadata changed.
+    Updated(u64),
+    /// The path was tracked and did not change.
+    Unchanged,
+    /// The path was detected as a tracked file that was moved.
+    Moved(u64),
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct AddReport {
+    pub added_count: usize,
+    pub added_bytes: u64,
+    pub updated_count: usize,
+    pub updated_bytes: u64,
+    pub moved_count: usize,
+    pub moved_bytes: u64,
+    pub unchanged_count: usize,
+    /// Paths that could not be added. The errors are logged.
+    pub failed_count: usize,
+}
+
+impl AddOutcome {
+    /// Bytes of content the path has, nothing for unchanged paths.
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            AddOutcome::Added(bytes) | AddOutcome::Updated(bytes) | AddOutcome::Moved(bytes) => bytes,
+            AddOutcome::Unchanged => 0,
+        }
+    }
+}
+
+impl AddReport {
+    pub fn record(&mut self, outcome: AddOutcome) {
+        match outcome {
+            AddOutcome::Added(bytes) => {
+                self.added_count += 1;
+                self.added_bytes += bytes;
+            }
+            AddOutcome::Updated(bytes) => {
+                self.updated_count += 1;
+                self.updated_bytes += bytes;
+            }
+            AddOutcome::Unchanged => self.unchanged_count += 1,
+            AddOutcome::Moved(bytes) => {
+                self.moved_count += 1;
+                self.moved_bytes += bytes;
+            }
+        }
+    }
+}
+
+impl fmt::Display for AddReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Added: {} ({} bytes)", self.added_count, self.added_bytes)?;
+        write!(f, "\nUpdated: {} ({} bytes)", self.updated_count, self.updated_bytes)?;
+        write!(f, "\nMoved: {} ({} bytes)", self.moved_count, self.moved_bytes)?;
+        write!(f, "\nUnchanged: {}", self.unchanged_count)?;
+
+        if self.failed_count > 0 {
+            write!(f, "\nFailed: {}", self.failed_count)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use failure::{
+    Error,
+    ResultExt,
+};
+use libc;
+use std::collections::BTreeMap;
+use std::ffi::{
+    CStr,
+    CString,
+};
+use std::fmt::Debug;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+/// Extended attributes that hold POSIX ACLs.
+const ACL_PREFIX: &str = "system.posix_acl_";
+
+/// Size of the buffer for the strings returned by passwd and group lookups.
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Which extended attributes are read from files.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct XattrFilter {
+    /// All attributes except those in the `system` and `security`
+    /// namespaces, for example `user.*` tags.
+    pub xattrs: bool,
+    /// POSIX ACLs, stored in `system.posix_acl_access` and
+    /// `system.posix_acl_default`.
+    pub acls: bool,
+}
+
+impl XattrFilter {
+    pub fn is_empty(&self) -> bool {
+        !self.xattrs && !self.acls
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        if name.starts_with(ACL_PREFIX) {
+            return self.acls;
+        }
+
+        if name.starts_with("system.") || name.starts_with("security.") {
+            return false;
+        }
+
+        self.xattrs
+    }
+}
+
+/// Reads the extended attributes of `path` without following symlinks.
+/// Attributes with names that are not valid UTF-8 are skipped.
+pub fn read_xattrs<P: AsRef<Path> + Debug>(path: P, filter: XattrFilter) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+    let mut out = BTreeMap::default();
+
+    if filter.is_empty() {
+        return Ok(out);
+    }
+
+    let c_path = path_to_cstring(&path)?;
+
+    let names = match read_buffer(|buffer, size| unsafe { libc::llistxattr(c_path.as_ptr(), buffer as *mut libc::c_char, size) }) {
+        Ok(names) => names,
+        // Filesystems without extended attributes have none to preserve.
+        Err(ref err) if is_unsupported(err) => {
+            debug!("{:?} is on a filesystem without extended attributes", path);
+            return Ok(out);
+        }
+        Err(err) => Err(err).context(format_err!("can not list extended attributes of {:?}", path))?,
+    };
+
+    for name in names.split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+        let name_string = match String::from_utf8(name.to_vec()) {
+            Ok(name) => name,
+            Err(_) => {
+                warn!("skipping extended attribute {:?} of {:?}, its name is not valid utf8", name, path);
+                continue;
+            }
+        };
+
+        if !filter.matches(&name_string) {
+            continue;
+        }
+
+        let c_name = CString::new(name.to_vec())?;
+        let value = read_buffer(|buffer, size| unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buffer, size) })
+            .context(format_err!("can not read extended attribute {} of {:?}", name_string, path))?;
+
+        out.insert(name_string, value);
+    }
+
+    Ok(out)
+}
+
+/// Sets the extended attributes on `path` without following symlinks.
+/// Attributes that are not in `xattrs` are left alone.
+pub fn write_xattrs<P: AsRef<Path> + Debug>(path: P, xattrs: &BTreeMap<String, Vec<u8>>) -> Result<(), Error> {
+    let c_path = path_to_cstring(&path)?;
+
+    for (name, value) in xattrs {
+        let c_name = CString::new(name.as_bytes())?;
+
+        let result = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+
+        if result != 0 {
+            Err(io::Error::last_os_error()).context(format_err!("can not set extended attribute {} of {:?}", name, path))?
+        }
+    }
+
+    Ok(())
+}
+
+/// Changes the owner of `path` without following symlinks.
+pub fn set_owner<P: AsRef<Path> + Debug>(path: P, uid: u32, gid: u32) -> Result<(), Error> {
+    let c_path = path_to_cstring(&path)?;
+
+    if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+        Err(io::Error::last_os_error()).context(format_err!("can not change owner of {:?}", path))?
+    }
+
+    Ok(())
+}
+
+/// Sets the modified time and, if given, the access time of `path` without
+/// following symlinks. The access time is left alone otherwise.
+pub fn set_times<P: AsRef<Path> + Debug>(path: P, modified: SystemTime, accessed: Option<SystemTime>) -> Result<(), Error> {
+    let c_path = path_to_cstring(&path)?;
+
+    let accessed = match accessed {
+        Some(accessed) => to_timespec(accessed)?,
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    };
+
+    let times = [accessed, to_timespec(modified)?];
+
+    let result = unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) };
+    if result != 0 {
+        Err(io::Error::last_os_error()).context(format_err!("can not set times of {:?}", path))?
+    }
+
+    Ok(())
+}
+
+fn to_timespec(time: SystemTime) -> Result<libc::timespec, Error> {
+    let duration = time.duration_since(UNIX_EPOCH)
+        .map_err(|_| format_err!("can not set time {:?} from before the unix epoch", time))?;
+
+    Ok(libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    })
+}
+
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut entry: libc::passwd = unsafe { ::std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+
+    let code = unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 || result.is_null() {
+        return None;
+    }
+
+    c_string_to_string(entry.pw_name)
+}
+
+pub fn group_name(gid: u32) -> Option<String> {
+    let mut entry: libc::group = unsafe { ::std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+
+    let code = unsafe { libc::getgrgid_r(gid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 || result.is_null() {
+        return None;
+    }
+
+    c_string_to_string(entry.gr_name)
+}
+
+pub fn user_id(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mut entry: libc::passwd = unsafe { ::std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+
+    let code = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 || result.is_null() {
+        return None;
+    }
+
+    Some(entry.pw_uid)
+}
+
+pub fn group_id(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    let mut entry: libc::group = unsafe { ::std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+
+    let code = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 || result.is_null() {
+        return None;
+    }
+
+    Some(entry.gr_gid)
+}
+
+/// Calls `read` first without a buffer to get the size and then with a
+/// buffer of that size. Retries when the size grew in between.
+fn read_buffer<F>(read: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let size = read(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buffer = vec![0u8; size as usize];
+        let read_size = read(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+        if read_size >= 0 {
+            buffer.truncate(read_size as usize);
+            return Ok(buffer);
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOTSUP) || err.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+fn path_to_cstring<P: AsRef<Path> + Debug>(path: P) -> Result<CString, Error> {
+    let c_path = CString::new(path.as_ref().as_os_str().as_bytes()).context(format_err!("path {:?} contains a nul byte", path))?;
+
+    Ok(c_path)
+}
+
+fn c_string_to_string(pointer: *const libc::c_char) -> Option<String> {
+    if pointer.is_null() {
+        return None;
+    }
+
+    unsafe { CStr::from_ptr(pointer) }
+        .to_str()
+        .ok()
+        .map(String::from)
+}
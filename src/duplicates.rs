@@ -0,0 +1,37 @@
+use output::serialize_paths;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Serialize, Debug)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub len: u64,
+    #[serde(serialize_with = "serialize_paths")]
+    pub paths: BTreeSet<PathBuf>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DuplicatesReport {
+    pub groups: Vec<DuplicateGroup>,
+    /// Bytes that would be freed if every group only had one copy.
+    pub duplicate_bytes: u64,
+}
+
+impl fmt::Display for DuplicatesReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Duplicate Groups: {}", self.groups.len())?;
+        write!(f, "\nDuplicate Bytes: {}", self.duplicate_bytes)?;
+
+        for group in &self.groups {
+            let paths = group
+                .paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\n{} ({} bytes):\n{}", group.hash, group.len, paths)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use failure::Error;
+use output::{
+    serialize_path,
+    serialize_paths,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct FsckOptions {
+    /// Only compare size and modified time instead of rehashing the content.
+    pub fast: bool,
+    /// Fix problems in the index and working tree where possible.
+    pub repair: bool,
+    /// Replace the content of corrupted files with an intact copy of the
+    /// same content from another path. Only files whose stat data still
+    /// matches the index are replaced so edits are never overwritten.
+    pub repair_from_duplicates: bool,
+    /// Continue from the checkpoint left behind by an interrupted run.
+    pub resume: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubOptions {
+    /// Stop verifying after this much time has passed.
+    pub max_duration: Option<Duration>,
+    /// Stop verifying after this many bytes have been hashed.
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Intact,
+    /// The content differs but the stat data still matches the index, so
+    /// the file changed without being written to.
+    Corrupted,
+    /// The file was written to since it was added.
+    Modified,
+    Missing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FsckCheckpoint {
+    pub last_path: PathBuf,
+}
+
+/// A path that could not be verified, for example because it can not be
+/// read.
+#[derive(Serialize, Debug)]
+pub struct PathError {
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl PathError {
+    /// Logs the error with all its causes and keeps it for the report.
+    pub fn new(path: PathBuf, err: &Error) -> PathError {
+        let error = err.causes()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+
+        error!("{}", error);
+
+        PathError {
+            path: path,
+            error: error,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct FsckReport {
+    pub checked_count: usize,
+    pub checked_bytes: u64,
+    pub skipped_count: usize,
+    pub undecodable_entries: usize,
+    #[serde(serialize_with = "serialize_paths")]
+    pub corrupted_paths: BTreeSet<PathBuf>,
+    #[serde(serialize_with = "serialize_paths")]
+    pub missing_paths: BTreeSet<PathBuf>,
+    /// Files that were changed since they were added. These are not
+    /// problems of the repository, `add` records the changes.
+    #[serde(serialize_with = "serialize_paths")]
+    pub modified_paths: BTreeSet<PathBuf>,
+    #[serde(serialize_with = "serialize_paths")]
+    pub missing_directories: BTreeSet<PathBuf>,
+    #[serde(serialize_with = "serialize_paths")]
+    pub repaired_paths: BTreeSet<PathBuf>,
+    /// Hashes of objects in the object store whose content does not match
+    /// their hash.
+    pub corrupted_objects: BTreeSet<String>,
+    /// Hashes of content that is present in the working tree but has no
+    /// object in the object store.
+    pub missing_objects: BTreeSet<String>,
+    /// Hashes of corrupted or missing objects that were stored again from
+    /// an intact copy in the working tree.
+    pub repaired_objects: BTreeSet<String>,
+    pub errors: Vec<PathError>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.undecodable_entries == 0 && self.corrupted_paths.is_empty() && self.missing_paths.is_empty()
+            && self.missing_directories.is_empty() && self.corrupted_objects.is_empty()
+            && self.missing_objects.is_empty() && self.errors.is_empty()
+    }
+
+    /// Records that `path` could not be verified. The check continues with
+    /// the other paths.
+    pub fn record_error(&mut self, path: PathBuf, err: &Error) {
+        self.errors.push(PathError::new(path, err));
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Paths Checked: {}", self.checked_count)?;
+        write!(f, "\nBytes Checked: {}", self.checked_bytes)?;
+
+        if self.skipped_count != 0 {
+            write!(f, "\nPaths Skipped: {}", self.skipped_count)?;
+        }
+
+        if self.undecodable_entries != 0 {
+            write!(f, "\nUndecodable Index Entries: {}", self.undecodable_entries)?;
+        }
+
+        if !self.corrupted_paths.is_empty() {
+            let paths = self.corrupted_paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nCorrupted Paths:\n{}", paths)?;
+        }
+
+        if !self.missing_paths.is_empty() {
+            let paths = self.missing_paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nMissing Paths:\n{}", paths)?;
+        }
+
+        if !self.modified_paths.is_empty() {
+            let paths = self.modified_paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nModified Paths:\n{}", paths)?;
+        }
+
+        if !self.missing_directories.is_empty() {
+            let paths = self.missing_directories
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nMissing Directories:\n{}", paths)?;
+        }
+
+        if !self.repaired_paths.is_empty() {
+            let paths = self.repaired_paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nRepaired Paths:\n{}", paths)?;
+        }
+
+        if !self.corrupted_objects.is_empty() {
+            let hashes = self.corrupted_objects
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{}\n", acc, x));
+
+            write!(f, "\nCorrupted Objects:\n{}", hashes)?;
+        }
+
+        if !self.missing_objects.is_empty() {
+            let hashes = self.missing_objects
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{}\n", acc, x));
+
+            write!(f, "\nMissing Objects:\n{}", hashes)?;
+        }
+
+        if !self.repaired_objects.is_empty() {
+            let hashes = self.repaired_objects
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{}\n", acc, x));
+
+            write!(f, "\nRepaired Objects:\n{}", hashes)?;
+        }
+
+        if !self.errors.is_empty() {
+            let errors = self.errors
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}: {}\n", acc, x.path, x.error));
+
+            write!(f, "\nErrors:\n{}", errors)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct GcOptions {
+    /// Only report unreferenced objects instead of removing them.
+    pub dry_run: bool,
+    /// Objects that were modified more recently than this are kept even when
+    /// they are unreferenced, they might belong to an operation that is still
+    /// running.
+    pub grace_period: Duration,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct GcReport {
+    pub referenced_hashes: usize,
+    pub scanned_count: usize,
+    /// Unreferenced objects that were kept because of the grace period.
+    pub recent_count: usize,
+    /// Hashes of the unreferenced objects that were removed or, in a dry
+    /// run, would be removed.
+    pub unreferenced_hashes: Vec<String>,
+    pub unreferenced_bytes: u64,
+    pub dry_run: bool,
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Referenced Hashes: {}", self.referenced_hashes)?;
+        write!(f, "\nScanned Objects: {}", self.scanned_count)?;
+        write!(f, "\nKept Recent Objects: {}", self.recent_count)?;
+
+        let action = if self.dry_run { "Unreferenced" } else { "Removed" };
+        write!(
+            f,
+            "\n{} Objects: {} ({} bytes)",
+            action,
+            self.unreferenced_hashes.len(),
+            self.unreferenced_bytes
+        )?;
+
+        for hash in &self.unreferenced_hashes {
+            write!(f, "\n\t{}", hash)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Hashes of inodes with multiple hardlinks that were already read while
+/// adding, so the other links to the same inode do not have to be hashed
+/// again. Keyed by device and inode.
+#[derive(Default)]
+pub struct HardlinkHashes {
+    hashes: Mutex<BTreeMap<(u64, u64), String>>,
+}
+
+impl HardlinkHashes {
+    pub fn get(&self, device: u64, inode: u64) -> Option<String> {
+        self.hashes.lock().unwrap().get(&(device, inode)).cloned()
+    }
+
+    pub fn insert(&self, device: u64, inode: u64, hash: String) {
+        self.hashes.lock().unwrap().insert((device, inode), hash);
+    }
+}
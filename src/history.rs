@@ -0,0 +1,142 @@
+use output::serialize_path;
+use repofile::RepoFile;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use uuid::Uuid;
+use uuidbytes;
+
+/// A version of a path. A new version is recorded with every journaled change
+/// of the entry, the full entry is looked up in the journal by `sequence`.
+#[derive(Serialize, Debug, Clone)]
+pub struct FileVersion {
+    /// Sequence number of the journal entry that recorded the version. Used
+    /// to select the version when restoring.
+    pub sequence: u64,
+    /// UUID of the repository the version was recorded in.
+    pub origin: Uuid,
+    pub time: SystemTime,
+    pub hash: Option<String>,
+    pub len: u64,
+    pub modified: SystemTime,
+    pub permissions: u32,
+    /// Identity of the file the version belongs to. Stays the same when the
+    /// file is modified or moved.
+    pub uuid: Uuid,
+}
+
+/// How a `FileVersion` is stored in the history, with the uuids as their raw
+/// bytes like in `StoredFile`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredVersion {
+    sequence: u64,
+    #[serde(with = "uuidbytes")]
+    origin: Uuid,
+    time: SystemTime,
+    hash: Option<String>,
+    len: u64,
+    modified: SystemTime,
+    permissions: u32,
+    #[serde(with = "uuidbytes")]
+    uuid: Uuid,
+}
+
+impl<'a> From<&'a FileVersion> for StoredVersion {
+    fn from(version: &FileVersion) -> StoredVersion {
+        StoredVersion {
+            sequence: version.sequence,
+            origin: version.origin,
+            time: version.time,
+            hash: version.hash.clone(),
+            len: version.len,
+            modified: version.modified,
+            permissions: version.permissions,
+            uuid: version.uuid,
+        }
+    }
+}
+
+impl From<StoredVersion> for FileVersion {
+    fn from(version: StoredVersion) -> FileVersion {
+        FileVersion {
+            sequence: version.sequence,
+            origin: version.origin,
+            time: version.time,
+            hash: version.hash,
+            len: version.len,
+            modified: version.modified,
+            permissions: version.permissions,
+            uuid: version.uuid,
+        }
+    }
+}
+
+impl FileVersion {
+    pub fn new(sequence: u64, origin: Uuid, time: SystemTime, file: &RepoFile) -> FileVersion {
+        FileVersion {
+            sequence: sequence,
+            origin: origin,
+            time: time,
+            hash: file.hash.clone(),
+            len: file.len,
+            modified: file.modified,
+            permissions: file.permissions,
+            uuid: file.uuid,
+        }
+    }
+}
+
+/// Selects the version `restore` brings back.
+#[derive(Debug)]
+pub enum RestoreVersion {
+    /// The version with this sequence number.
+    Sequence(u64),
+    /// The latest version that was recorded at or before this time.
+    At(SystemTime),
+}
+
+impl RestoreVersion {
+    pub fn select<'a>(&self, versions: &'a [FileVersion]) -> Option<&'a FileVersion> {
+        match *self {
+            RestoreVersion::Sequence(sequence) => versions.iter().find(|version| version.sequence == sequence),
+            RestoreVersion::At(time) => versions.iter().rev().find(|version| version.time <= time),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileHistory {
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    /// Oldest version first.
+    pub versions: Vec<FileVersion>,
+}
+
+impl fmt::Display for FileHistory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "History of {:?}: {} versions", self.path, self.versions.len())?;
+
+        for version in &self.versions {
+            let time = version
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+
+            write!(
+                f,
+                "\n{}\t{}\t{}\t{}\t{} bytes",
+                version.sequence,
+                time,
+                version.origin,
+                version.hash.as_ref().map(|hash| hash.as_str()).unwrap_or("-"),
+                version.len
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use failure::{
+    Error,
+    ResultExt,
+};
+use ignore::gitignore::{
+    Gitignore,
+    GitignoreBuilder,
+};
+use ignore::Match;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
+pub const IGNORE_FILE_NAME: &str = ".syncustignore";
+
+/// Decides which paths are ignored based on `.syncustignore` files in the
+/// repository and the ignore list from the repository settings. The rules
+/// follow the gitignore format. A file in a deeper directory takes precedence
+/// over files further up and all of them take precedence over the settings.
+pub struct IgnoreFilter {
+    root: PathBuf,
+    settings: Gitignore,
+    directories: RefCell<BTreeMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl IgnoreFilter {
+    pub fn new<P: AsRef<Path> + Debug>(root: P, patterns: &[String]) -> Result<IgnoreFilter, Error> {
+        let mut builder = GitignoreBuilder::new(&root);
+
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern)
+                .context(format_err!("can not parse ignore pattern {}", pattern))?;
+        }
+
+        let settings = builder.build().context("can not build ignore patterns from settings")?;
+
+        Ok(IgnoreFilter {
+            root: root.as_ref().to_path_buf(),
+            settings: settings,
+            directories: RefCell::new(BTreeMap::default()),
+        })
+    }
+
+    /// Accepts paths below the repository root, the way `WalkDir` returns
+    /// them, and paths relative to the root. Both are matched relative to
+    /// the root so it does not matter how the repository path was given.
+    pub fn is_ignored<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+        let path = path.as_ref();
+
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) if path.is_relative() => path,
+            Err(_) => return false,
+        };
+
+        let relative = relative
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>();
+
+        if relative.as_os_str().is_empty() {
+            return false;
+        }
+
+        let path = &self.root.join(relative);
+
+        let mut parent = path.parent();
+        while let Some(directory) = parent {
+            match self.directory_match(directory, path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+
+            if directory == self.root {
+                break;
+            }
+
+            parent = directory.parent();
+        }
+
+        self.settings.matched(path, is_dir).is_ignore()
+    }
+
+    fn directory_match(&self, directory: &Path, path: &Path, is_dir: bool) -> Match<()> {
+        let mut directories = self.directories.borrow_mut();
+
+        let gitignore = directories
+            .entry(directory.to_path_buf())
+            .or_insert_with(|| load_ignore_file(directory));
+
+        match *gitignore {
+            Some(ref gitignore) => match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => Match::Ignore(()),
+                Match::Whitelist(_) => Match::Whitelist(()),
+                Match::None => Match::None,
+            },
+            None => Match::None,
+        }
+    }
+}
+
+fn load_ignore_file(directory: &Path) -> Option<Gitignore> {
+    let ignore_path = directory.join(IGNORE_FILE_NAME);
+    if !ignore_path.is_file() {
+        return None;
+    }
+
+    let (gitignore, err) = Gitignore::new(&ignore_path);
+    if let Some(err) = err {
+        warn!("problem while reading ignore file {:?}: {}", ignore_path, err);
+    }
+
+    Some(gitignore)
+}
@@ -7,63 +7,894 @@ use failure::{
     Error,
     ResultExt,
 };
-use repofile::RepoFile;
+use history::{
+    FileVersion,
+    StoredVersion,
+};
+use journal::{
+    decode_sequence,
+    sequence_key,
+    JournalChange,
+    JournalEntry,
+    JournalRecord,
+};
+use output::serialize_path;
+use repofile::{
+    RepoFile,
+    StoredFile,
+};
 use rocksdb::{
+    ColumnFamily,
+    DBIterator,
+    Direction,
     IteratorMode,
+    Options,
+    WriteBatch,
     DB,
 };
-use std::collections::BTreeMap;
+use snapshot::Snapshot;
+use sparse;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::path::Path;
+use std::fs::{
+    read_link,
+    symlink_metadata,
+};
+use std::os::unix::ffi::{
+    OsStrExt,
+    OsStringExt,
+};
+use std::os::unix::fs::MetadataExt;
+use std::path::{
+    Component,
+    Path,
+};
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use uuid::Uuid;
+
+/// Version of the index format, stored as `version` in the repository
+/// settings. Has to be increased with every change of the keys or of the
+/// encoding of the stored values.
+///
+/// 1. Keys are bincode encoded paths, entries only record the hash, the
+///    type, the length, the modification time, the permissions and the uuid
+///    as string.
+/// 2. Keys are the path components separated by `KEY_SEPARATOR`, entries
+///    record the stat data, the allocated size, owners and attributes and
+///    store uuids as their raw bytes. Adds the hash index, journal,
+///    checkpoints, history and snapshots.
+pub const FORMAT_VERSION: usize = 2;
+
+/// Separates the path components in index keys. It can not be part of a file
+/// name and sorts before every other byte so the order of the keys in RocksDB
+/// is the same as the order of the paths.
+const KEY_SEPARATOR: u8 = 0;
+
+/// Maps content hashes to the paths that reference them. Keys are the hash
+/// followed by `KEY_SEPARATOR` and the path key, values are empty.
+const HASHES_COLUMN_FAMILY: &str = "hashes";
+
+/// Records every change of the entries. Keys are sequence numbers, values
+/// are bincode encoded `JournalRecord`s.
+const JOURNAL_COLUMN_FAMILY: &str = "journal";
+
+/// The last journal sequence number each remote has seen. Keys are remote
+/// names, values sequence numbers encoded like journal keys.
+const CHECKPOINTS_COLUMN_FAMILY: &str = "checkpoints";
+
+/// Keeps the versions of every path. Keys are the path key followed by two
+/// `KEY_SEPARATOR`s and the sequence number of the version, values are
+/// bincode encoded `FileVersion`s. Path components can not be empty so the
+/// double separator can not be part of a path key.
+const HISTORY_COLUMN_FAMILY: &str = "history";
+
+/// Named snapshots. Keys are the snapshot names, values are bincode encoded
+/// `Snapshot`s.
+const SNAPSHOTS_COLUMN_FAMILY: &str = "snapshots";
+
+/// Column families next to the default one that holds the entries. New ones
+/// have to be appended at the end so older indexes can be upgraded.
+const COLUMN_FAMILIES: &[&str] = &[
+    HASHES_COLUMN_FAMILY,
+    JOURNAL_COLUMN_FAMILY,
+    CHECKPOINTS_COLUMN_FAMILY,
+    HISTORY_COLUMN_FAMILY,
+    SNAPSHOTS_COLUMN_FAMILY,
+];
+
+/// Entries as they were stored by version 1 of the format.
+#[derive(Deserialize, Debug)]
+struct LegacyEntry {
+    hash: Option<String>,
+    is_dir: bool,
+    is_symlink: bool,
+    len: u64,
+    modified: SystemTime,
+    permissions: u32,
+    uuid: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IndexEntry {
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    pub file: RepoFile,
+}
 
 pub struct Index {
     db: DB,
+    /// UUID of the repository, recorded as origin of all journal entries
+    /// written through this index.
+    origin: Uuid,
+    /// The last sequence number in the journal. The lock is held while
+    /// writing so sequence numbers are in the same order as the writes.
+    sequence: Mutex<u64>,
+}
+
+#[derive(Debug)]
+pub enum IndexUpdate {
+    Set(PathBuf, RepoFile),
+    /// Changes only bookkeeping that matters to this repository alone, like
+    /// the cached stat data, when the content was verified or whether it is
+    /// present. Not recorded in the journal or the history as there is
+    /// nothing for peers to sync. The content must stay the same.
+    SetLocal(PathBuf, RepoFile),
+    Delete(PathBuf),
+}
+
+/// A change of a single key, see `IndexUpdate`.
+enum KeyChange {
+    Put(Vec<u8>, RepoFile),
+    PutLocal(Vec<u8>, RepoFile),
+    Delete(Vec<u8>),
+}
+
+/// A write batch together with the hashes of the entries it changes so
+/// multiple updates of the same entry keep the hash index consistent.
+#[derive(Default)]
+struct IndexBatch {
+    inner: WriteBatch,
+    hashes: BTreeMap<Vec<u8>, Option<String>>,
+}
+
+/// Iterates over journal entries in sequence order.
+pub struct JournalIter {
+    inner: DBIterator,
+}
+
+impl Iterator for JournalIter {
+    type Item = Result<JournalEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, data) = self.inner.next()?;
+
+        Some(decode_journal_entry(&key, &data))
+    }
+}
+
+/// Iterates over index entries in path order. Entries are only decoded when
+/// they are reached so the index does not have to fit into memory.
+pub struct IndexIter {
+    inner: DBIterator,
+    end: Option<Vec<u8>>,
+}
+
+impl Iterator for IndexIter {
+    type Item = Result<(PathBuf, RepoFile), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, data) = self.inner.next()?;
+
+        if let Some(ref end) = self.end {
+            if &*key >= end.as_slice() {
+                return None;
+            }
+        }
+
+        let entry = decode_file(&data)
+            .map(|file| (decode_key(&key), file))
+            .map_err(|err| format_err!("can not decode index entry {:?}: {}", decode_key(&key), err));
+
+        Some(entry)
+    }
 }
 
 impl Index {
-    pub fn open<P: AsRef<Path> + Debug>(path: P) -> Result<Index, Error> {
-        let db = DB::open_default(path)?;
-        Ok(Index { db: db })
+    pub fn open<P: AsRef<Path> + Debug>(path: P, origin: Uuid) -> Result<Index, Error> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+
+        // RocksDB refuses to open a database without listing all its column
+        // families and fails when listing ones that do not exist yet. So try
+        // with fewer column families until opening works and create the
+        // missing ones afterwards.
+        let mut existing = COLUMN_FAMILIES.len();
+        let mut db = loop {
+            match DB::open_cf(&options, &path, &COLUMN_FAMILIES[..existing]) {
+                Ok(db) => break db,
+                Err(err) => {
+                    if existing == 0 {
+                        Err(err).context(format_err!("can not open index {:?}", path))?
+                    }
+
+                    existing -= 1;
+                }
+            }
+        };
+
+        for name in &COLUMN_FAMILIES[existing..] {
+            debug!("creating column family {} in index {:?}", name, path);
+            db.create_cf(name, &Options::default())
+                .context(format_err!("can not create column family {}", name))?;
+        }
+
+        let index = Index {
+            db: db,
+            origin: origin,
+            sequence: Mutex::new(0),
+        };
+
+        index.load_sequence().context("can not load last journal sequence")?;
+
+        if existing == 0 {
+            index
+                .rebuild_hashes()
+                .context("can not build hash index")?;
+        }
+
+        if existing < 2 {
+            index
+                .rebuild_journal()
+                .context("can not build journal")?;
+        }
+
+        if existing < 4 {
+            index
+                .rebuild_history()
+                .context("can not build history")?;
+        }
+
+        Ok(index)
+    }
+
+    /// Converts the entries of a version 1 index at `path` to the current
+    /// format. The missing stat data is taken from the files in `root` where
+    /// they still exist, the change time is left unset so the content is
+    /// hashed again before it is trusted. Has to run before the index is
+    /// opened with `open`, which builds the other column families from the
+    /// converted entries.
+    pub fn migrate_v1<P: AsRef<Path> + Debug, Q: AsRef<Path>>(path: P, root: Q) -> Result<(), Error> {
+        let db = DB::open_default(&path).context(format_err!("can not open index {:?}", path))?;
+
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+
+        for (key, data) in db.iterator(IteratorMode::Start) {
+            // An interrupted migration leaves entries in the new format
+            // behind, those do not decode as a bincode string of exactly
+            // the length of the key.
+            let relative = match deserialize::<String>(&key) {
+                Ok(ref relative) if key.len() == relative.len() + 8 => PathBuf::from(relative),
+                _ => continue,
+            };
+
+            let legacy: LegacyEntry = match deserialize(&data) {
+                Ok(legacy) => legacy,
+                Err(_) => continue,
+            };
+
+            let file = migrate_entry(legacy, root.as_ref().join(&relative));
+            let data = encode_file(&file)?;
+
+            batch.delete(&key)?;
+            batch.put(&encode_key(&relative)?, &data)?;
+            count += 1;
+        }
+
+        db.write(batch).context("can not write migrated index entries")?;
+        info!("migrated {} entries of index {:?} from format version 1", count, path);
+
+        Ok(())
     }
 
     pub fn set<P: AsRef<Path> + Debug>(&self, path: P, file: &RepoFile) -> Result<(), Error> {
-        let key: Vec<u8> = serialize(&path.as_ref(), Infinite).context(format_err!("can not serialize path {:?} to bytes", path))?;
-        let data: Vec<u8> = serialize(&file, Infinite).context("can not serialize data to bytes")?;
+        self.write(vec![IndexUpdate::Set(path.as_ref().to_path_buf(), file.clone())])
+    }
+
+    /// Like `set` for changes of local bookkeeping only, see
+    /// `IndexUpdate::SetLocal`.
+    pub fn set_local<P: AsRef<Path> + Debug>(&self, path: P, file: &RepoFile) -> Result<(), Error> {
+        self.write(vec![IndexUpdate::SetLocal(path.as_ref().to_path_buf(), file.clone())])
+    }
 
-        self.db.put(&key, &data)?;
+    /// Applies all updates in one atomic write. Either all of them end up in
+    /// the index or none of them do.
+    pub fn write(&self, updates: Vec<IndexUpdate>) -> Result<(), Error> {
+        let changes = updates
+            .into_iter()
+            .map(|update| match update {
+                IndexUpdate::Set(path, file) => Ok(KeyChange::Put(encode_key(&path)?, file)),
+                IndexUpdate::SetLocal(path, file) => Ok(KeyChange::PutLocal(encode_key(&path)?, file)),
+                IndexUpdate::Delete(path) => Ok(KeyChange::Delete(encode_key(&path)?)),
+            })
+            .collect::<Result<_, Error>>()?;
+
+        self.write_keys(changes)
+    }
+
+    /// Writes the changes and the journal entries of the ones that are not
+    /// local in one batch.
+    fn write_keys(&self, changes: Vec<KeyChange>) -> Result<(), Error> {
+        let mut sequence = self.sequence.lock().unwrap();
+        let mut next = *sequence;
+        let mut batch = IndexBatch::default();
+        let time = SystemTime::now();
+
+        for change in changes {
+            match change {
+                KeyChange::Put(key, file) => {
+                    next += 1;
+                    self.batch_put(&mut batch, key.clone(), &file, next, time)?;
+                    self.batch_journal(&mut batch, next, time, key, Some(file))?;
+                }
+                KeyChange::PutLocal(key, file) => self.batch_put_local(&mut batch, key, &file)?,
+                KeyChange::Delete(key) => {
+                    next += 1;
+                    self.batch_delete(&mut batch, key.clone())?;
+                    self.batch_journal(&mut batch, next, time, key, None)?;
+                }
+            }
+        }
+
+        self.db.write(batch.inner)?;
+        *sequence = next;
+
+        Ok(())
+    }
+
+    fn batch_journal(&self, batch: &mut IndexBatch, sequence: u64, time: SystemTime, key: Vec<u8>, file: Option<RepoFile>) -> Result<(), Error> {
+        let record = JournalRecord {
+            origin: self.origin,
+            time: time,
+            key: key,
+            file: file.as_ref().map(StoredFile::from),
+        };
+
+        let data: Vec<u8> = serialize(&record, Infinite).context("can not serialize journal record to bytes")?;
+        batch
+            .inner
+            .put_cf(self.journal()?, &sequence_key(sequence), &data)?;
 
         Ok(())
     }
 
+    fn batch_put(&self, batch: &mut IndexBatch, key: Vec<u8>, file: &RepoFile, sequence: u64, time: SystemTime) -> Result<(), Error> {
+        let data = encode_file(file)?;
+        let hashes = self.hashes()?;
+
+        let old_hash = self.batch_hash(batch, &key)?;
+        if old_hash != file.hash {
+            if let Some(ref hash) = old_hash {
+                batch.inner.delete_cf(hashes, &hash_key(hash, &key))?;
+            }
+        }
+
+        // Every journaled change is a version, also the ones that only change
+        // the metadata or the target of a symlink.
+        let version = FileVersion::new(sequence, self.origin, time, file);
+        self.batch_version(batch, &key, &version)?;
+
+        if let Some(ref hash) = file.hash {
+            batch.inner.put_cf(hashes, &hash_key(hash, &key), &[])?;
+        }
+
+        batch.inner.put(&key, &data)?;
+        batch.hashes.insert(key, file.hash.clone());
+
+        Ok(())
+    }
+
+    /// Writes the entry without recording a version. Refuses changes of the
+    /// content, those have to be journaled.
+    fn batch_put_local(&self, batch: &mut IndexBatch, key: Vec<u8>, file: &RepoFile) -> Result<(), Error> {
+        if self.batch_hash(batch, &key)? != file.hash {
+            bail!("local update of {:?} changes its content", decode_key(&key))
+        }
+
+        let data = encode_file(file)?;
+        batch.inner.put(&key, &data)?;
+        batch.hashes.insert(key, file.hash.clone());
+
+        Ok(())
+    }
+
+    fn batch_delete(&self, batch: &mut IndexBatch, key: Vec<u8>) -> Result<(), Error> {
+        // Undecodable entries can not be in the hash index so there is
+        // nothing else to remove for them.
+        if let Ok(Some(hash)) = self.batch_hash(batch, &key) {
+            batch.inner.delete_cf(self.hashes()?, &hash_key(&hash, &key))?;
+        }
+
+        batch.inner.delete(&key)?;
+        batch.hashes.insert(key, None);
+
+        Ok(())
+    }
+
+    fn batch_version(&self, batch: &mut IndexBatch, key: &[u8], version: &FileVersion) -> Result<(), Error> {
+        let data: Vec<u8> = serialize(&StoredVersion::from(version), Infinite).context("can not serialize file version to bytes")?;
+        batch
+            .inner
+            .put_cf(self.versions()?, &version_key(key, version.sequence), &data)?;
+
+        Ok(())
+    }
+
+    /// Returns the hash the entry will have before the batch gets applied.
+    /// Writes earlier in the same batch take precedence over the database.
+    fn batch_hash(&self, batch: &IndexBatch, key: &[u8]) -> Result<Option<String>, Error> {
+        if let Some(hash) = batch.hashes.get(key) {
+            return Ok(hash.clone());
+        }
+
+        Ok(self.get_key(key)?.and_then(|old| old.hash))
+    }
+
     pub fn get<P: AsRef<Path> + Debug>(&self, path: P) -> Result<RepoFile, Error> {
-        let key: Vec<u8> = serialize(&path.as_ref(), Infinite).context("can not serialize key to bytes")?;
+        match self.get_key(&encode_key(&path)?)? {
+            Some(decoded) => Ok(decoded),
+            None => bail!("key not found in index"),
+        }
+    }
 
-        match self.db.get(&key)? {
+    fn get_key(&self, key: &[u8]) -> Result<Option<RepoFile>, Error> {
+        match self.db.get(key)? {
             Some(data) => {
-                let decoded: RepoFile = deserialize(&data)?;
-                Ok(decoded)
+                let decoded = decode_file(&data)?;
+                Ok(Some(decoded))
             }
-            None => bail!("key not found in index"),
+            None => Ok(None),
         }
     }
 
-    pub fn contains<P: AsRef<Path> + Debug>(&self, path: P) -> bool {
-        debug!("contains: checking if index contains key {:?}", path);
-        match serialize(&path.as_ref(), Infinite) {
-            Ok(key) => {
-                debug!("contains: serialized key to bytes trying to get from index");
-
-                match self.db.get(&key) {
-                    Ok(option) => match option {
-                        Some(_) => true,
-                        None => false,
-                    },
-                    Err(_) => false,
+    pub fn delete<P: AsRef<Path> + Debug>(&self, path: P) -> Result<(), Error> {
+        self.delete_raw(&encode_key(&path)?)
+    }
+
+    pub fn delete_raw(&self, key: &[u8]) -> Result<(), Error> {
+        self.write_keys(vec![KeyChange::Delete(key.to_vec())])
+    }
+
+    /// Returns the journal entries with a sequence number greater than
+    /// `sequence`. Passing 0 returns the whole journal.
+    pub fn changes_since(&self, sequence: u64) -> Result<JournalIter, Error> {
+        let start = sequence_key(sequence + 1);
+        let inner = self.db
+            .iterator_cf(self.journal()?, IteratorMode::From(&start, Direction::Forward))?;
+
+        Ok(JournalIter { inner: inner })
+    }
+
+    /// Returns the entry as it was set by the journal entry with the
+    /// sequence number, with all the attributes a `FileVersion` does not
+    /// keep. None if the journal entry deleted the path.
+    pub fn recorded_file(&self, sequence: u64) -> Result<Option<RepoFile>, Error> {
+        let key = sequence_key(sequence);
+        let data = self.db
+            .get_cf(self.journal()?, &key)?
+            .ok_or_else(|| format_err!("journal entry {} does not exist", sequence))?;
+
+        match decode_journal_entry(&key, &data)?.change {
+            JournalChange::Set(file) => Ok(Some(file)),
+            JournalChange::Delete => Ok(None),
+        }
+    }
+
+    /// Returns the recorded versions of the path, oldest first. Versions are
+    /// kept after the path is deleted from the index.
+    pub fn history<P: AsRef<Path> + Debug>(&self, path: P) -> Result<Vec<FileVersion>, Error> {
+        let prefix = history_prefix(&encode_key(&path)?);
+
+        let mut out = Vec::new();
+        for (key, data) in self.db
+            .iterator_cf(self.versions()?, IteratorMode::From(&prefix, Direction::Forward))?
+        {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let version = decode_version(&data).map_err(|err| format_err!("can not decode version of {:?}: {}", path, err))?;
+            out.push(version);
+        }
+
+        Ok(out)
+    }
+
+    /// Returns the entries as they were after the change with the given
+    /// sequence number by replaying the journal.
+    pub fn entries_at(&self, sequence: u64) -> Result<BTreeMap<PathBuf, RepoFile>, Error> {
+        let mut out = BTreeMap::default();
+
+        for entry in self.changes_since(0)? {
+            let entry = entry?;
+            if entry.sequence > sequence {
+                break;
+            }
+
+            match entry.change {
+                JournalChange::Set(file) => {
+                    out.insert(entry.path, file);
+                }
+                JournalChange::Delete => {
+                    out.remove(&entry.path);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Records the current state of the index under `name`.
+    pub fn create_snapshot(&self, name: &str) -> Result<Snapshot, Error> {
+        // Holding the lock makes sure no write happens between reading the
+        // sequence number and storing the snapshot.
+        let sequence = self.sequence.lock().unwrap();
+
+        if self.snapshot(name)?.is_some() {
+            bail!("snapshot {} does already exist", name)
+        }
+
+        let snapshot = Snapshot {
+            name: name.to_string(),
+            created: SystemTime::now(),
+            sequence: *sequence,
+        };
+
+        let data: Vec<u8> = serialize(&snapshot, Infinite).context("can not serialize snapshot to bytes")?;
+        self.db
+            .put_cf(self.snapshots_column_family()?, name.as_bytes(), &data)?;
+
+        Ok(snapshot)
+    }
+
+    pub fn snapshot(&self, name: &str) -> Result<Option<Snapshot>, Error> {
+        match self.db
+            .get_cf(self.snapshots_column_family()?, name.as_bytes())?
+        {
+            Some(data) => {
+                let snapshot = deserialize(&data).map_err(|err| format_err!("can not decode snapshot {}: {}", name, err))?;
+                Ok(Some(snapshot))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns all snapshots ordered by name.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>, Error> {
+        let mut out = Vec::new();
+
+        for (key, data) in self.db
+            .iterator_cf(self.snapshots_column_family()?, IteratorMode::Start)?
+        {
+            let snapshot = deserialize(&data).map_err(|err| format_err!("can not decode snapshot {:?}: {}", key, err))?;
+            out.push(snapshot);
+        }
+
+        Ok(out)
+    }
+
+    pub fn delete_snapshot(&self, name: &str) -> Result<(), Error> {
+        if self.snapshot(name)?.is_none() {
+            bail!("snapshot {} does not exist", name)
+        }
+
+        self.db
+            .delete_cf(self.snapshots_column_family()?, name.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns every hash that is still needed: the hashes of the current
+    /// entries, of the versions in the history that were current at or after
+    /// `history_since` (all of them if it is `None`) and of all snapshots.
+    pub fn referenced_hashes(&self, history_since: Option<SystemTime>) -> Result<BTreeSet<String>, Error> {
+        let mut out = BTreeSet::default();
+
+        for (key, _) in self.db.iterator_cf(self.hashes()?, IteratorMode::Start)? {
+            let (hash, _) = split_hash_key(&key)?;
+            out.insert(hash);
+        }
+
+        // A version stays current until the next version of the same path so
+        // it has to be kept if that next version is recent enough. The last
+        // version of every path is always kept.
+        let mut previous: Option<(Vec<u8>, FileVersion)> = None;
+        for (key, data) in self.db.iterator_cf(self.versions()?, IteratorMode::Start)? {
+            let version = decode_version(&data).map_err(|err| format_err!("can not decode version {:?}: {}", key, err))?;
+            let path_key = key[..key.len().saturating_sub(8)].to_vec();
+
+            if let Some((previous_key, previous_version)) = previous.take() {
+                let retained = previous_key != path_key || match history_since {
+                    Some(since) => version.time >= since,
+                    None => true,
+                };
+
+                if retained {
+                    out.extend(previous_version.hash);
                 }
             }
+
+            previous = Some((path_key, version));
+        }
+
+        if let Some((_, version)) = previous {
+            out.extend(version.hash);
+        }
+
+        for snapshot in self.snapshots()? {
+            for (_, file) in self.entries_at(snapshot.sequence)? {
+                out.extend(file.hash);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Copies the entries of `source` into this empty index with `present`
+    /// unset, as none of the content is local yet. The journal and the
+    /// history are copied verbatim so the changes keep the sequence number,
+    /// origin and time they were recorded with.
+    pub fn import(&self, source: &Index) -> Result<(), Error> {
+        let mut sequence = self.sequence.lock().unwrap();
+        if *sequence != 0 || self.iter().next().is_some() {
+            bail!("can not import into an index that is not empty")
+        }
+
+        let mut batch = WriteBatch::default();
+
+        for (key, data) in source.db.iterator_cf(source.journal()?, IteratorMode::Start)? {
+            batch.put_cf(self.journal()?, &key, &data)?;
+        }
+
+        for (key, data) in source.db.iterator_cf(source.versions()?, IteratorMode::Start)? {
+            batch.put_cf(self.versions()?, &key, &data)?;
+        }
+
+        for entry in source.iter() {
+            let (path, mut file) = entry?;
+            file.present = false;
+
+            let key = encode_key(&path)?;
+            if let Some(ref hash) = file.hash {
+                batch.put_cf(self.hashes()?, &hash_key(hash, &key), &[])?;
+            }
+
+            let data = encode_file(&file)?;
+            batch.put(&key, &data)?;
+        }
+
+        self.db.write(batch)?;
+        *sequence = source.last_sequence();
+
+        Ok(())
+    }
+
+    /// The sequence number of the last change in the journal.
+    pub fn last_sequence(&self) -> u64 {
+        *self.sequence.lock().unwrap()
+    }
+
+    /// Returns the last sequence number the remote has seen or 0 if it has
+    /// not seen any changes yet.
+    pub fn checkpoint(&self, remote: &str) -> Result<u64, Error> {
+        match self.db.get_cf(self.checkpoints()?, remote.as_bytes())? {
+            Some(data) => decode_sequence(&data),
+            None => Ok(0),
+        }
+    }
+
+    pub fn set_checkpoint(&self, remote: &str, sequence: u64) -> Result<(), Error> {
+        self.db
+            .put_cf(self.checkpoints()?, remote.as_bytes(), &sequence_key(sequence))?;
+
+        Ok(())
+    }
+
+    /// Returns all paths whose content has the given hash.
+    pub fn paths_for_hash(&self, hash: &str) -> Result<BTreeSet<PathBuf>, Error> {
+        let mut prefix = hash.as_bytes().to_vec();
+        prefix.push(KEY_SEPARATOR);
+
+        let mut out = BTreeSet::default();
+        for (key, _) in self.db
+            .iterator_cf(self.hashes()?, IteratorMode::From(&prefix, Direction::Forward))?
+        {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            out.insert(decode_key(&key[prefix.len()..]));
+        }
+
+        Ok(out)
+    }
+
+    /// Returns all hashes that are referenced by more than one path.
+    pub fn duplicate_hashes(&self) -> Result<BTreeMap<String, BTreeSet<PathBuf>>, Error> {
+        let mut out = BTreeMap::default();
+
+        let mut current: Option<(String, BTreeSet<PathBuf>)> = None;
+        for (key, _) in self.db.iterator_cf(self.hashes()?, IteratorMode::Start)? {
+            let (hash, path) = split_hash_key(&key)?;
+
+            let same_hash = match current {
+                Some((ref current_hash, _)) => current_hash == &hash,
+                None => false,
+            };
+
+            if !same_hash {
+                if let Some((current_hash, paths)) = current.take() {
+                    if paths.len() > 1 {
+                        out.insert(current_hash, paths);
+                    }
+                }
+
+                current = Some((hash, BTreeSet::default()));
+            }
+
+            if let Some((_, ref mut paths)) = current {
+                paths.insert(path);
+            }
+        }
+
+        if let Some((current_hash, paths)) = current {
+            if paths.len() > 1 {
+                out.insert(current_hash, paths);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Fills the hash index from the entries. Used when upgrading an index
+    /// that was created before the hash index existed.
+    fn rebuild_hashes(&self) -> Result<(), Error> {
+        let hashes = self.hashes()?;
+
+        let mut batch = WriteBatch::default();
+        for entry in self.iter() {
+            let (path, file) = entry?;
+
+            if let Some(ref hash) = file.hash {
+                batch.put_cf(hashes, &hash_key(hash, &encode_key(&path)?), &[])?;
+            }
+        }
+
+        self.db.write(batch)?;
+
+        Ok(())
+    }
+
+    fn load_sequence(&self) -> Result<(), Error> {
+        let last = self.db
+            .iterator_cf(self.journal()?, IteratorMode::End)?
+            .next();
+
+        if let Some((key, _)) = last {
+            *self.sequence.lock().unwrap() = decode_sequence(&key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records all current entries in the journal. Used when upgrading an
+    /// index that was created before the journal existed so peers reading
+    /// the journal from the start see the whole index.
+    fn rebuild_journal(&self) -> Result<(), Error> {
+        let changes = self.iter()
+            .map(|entry| entry.and_then(|(path, file)| Ok((encode_key(&path)?, Some(file)))))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut sequence = self.sequence.lock().unwrap();
+        let mut next = *sequence;
+        let mut batch = IndexBatch::default();
+        let time = SystemTime::now();
+
+        for (key, file) in changes {
+            next += 1;
+            self.batch_journal(&mut batch, next, time, key, file)?;
+        }
+
+        self.db.write(batch.inner)?;
+        *sequence = next;
+
+        Ok(())
+    }
+
+    /// Fills the history by replaying the journal. Used when upgrading an
+    /// index that was created before the history existed.
+    fn rebuild_history(&self) -> Result<(), Error> {
+        let mut hashes: BTreeMap<Vec<u8>, Option<String>> = BTreeMap::default();
+        let mut batch = IndexBatch::default();
+
+        for entry in self.changes_since(0)? {
+            let entry = entry?;
+            let key = encode_key(&entry.path)?;
+
+            match entry.change {
+                JournalChange::Set(file) => {
+                    let changed = match hashes.get(&key) {
+                        Some(hash) => hash != &file.hash,
+                        None => file.hash.is_some(),
+                    };
+
+                    if changed {
+                        let version = FileVersion::new(entry.sequence, entry.origin, entry.time, &file);
+                        self.batch_version(&mut batch, &key, &version)?;
+                    }
+
+                    hashes.insert(key, file.hash);
+                }
+                JournalChange::Delete => {
+                    hashes.insert(key, None);
+                }
+            }
+        }
+
+        self.db.write(batch.inner)?;
+
+        Ok(())
+    }
+
+    fn hashes(&self) -> Result<ColumnFamily, Error> {
+        self.column_family(HASHES_COLUMN_FAMILY)
+    }
+
+    fn journal(&self) -> Result<ColumnFamily, Error> {
+        self.column_family(JOURNAL_COLUMN_FAMILY)
+    }
+
+    fn checkpoints(&self) -> Result<ColumnFamily, Error> {
+        self.column_family(CHECKPOINTS_COLUMN_FAMILY)
+    }
+
+    fn versions(&self) -> Result<ColumnFamily, Error> {
+        self.column_family(HISTORY_COLUMN_FAMILY)
+    }
+
+    fn snapshots_column_family(&self) -> Result<ColumnFamily, Error> {
+        self.column_family(SNAPSHOTS_COLUMN_FAMILY)
+    }
+
+    fn column_family(&self, name: &str) -> Result<ColumnFamily, Error> {
+        let column_family = self.db
+            .cf_handle(name)
+            .ok_or_else(|| format_err!("can not get column family {}", name))?;
+
+        Ok(column_family)
+    }
+
+    pub fn contains<P: AsRef<Path> + Debug>(&self, path: P) -> bool {
+        debug!("contains: checking if index contains key {:?}", path);
+
+        let key = match encode_key(&path) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+
+        match self.db.get(&key) {
+            Ok(option) => option.is_some(),
             Err(err) => {
-                warn!("can not serialize key to bytes: {}", err);
+                warn!("can not get key from index: {}", err);
                 false
             }
         }
@@ -75,29 +906,266 @@ impl Index {
     }
 
     pub fn debug_tracked_files(&self) -> Result<(), Error> {
-        let iter = self.db.iterator(IteratorMode::Start);
-
-        for (key, data) in iter {
-            let decoded_key: PathBuf = deserialize(&key)?;
-            let decoded_data: RepoFile = deserialize(&data)?;
+        for entry in self.iter() {
+            let (path, file) = entry?;
 
-            println!("key: {:?}\nvalue: {:#?}", decoded_key, decoded_data);
+            println!("key: {:?}\nvalue: {:#?}", path, file);
         }
 
         Ok(())
     }
 
+    pub fn iter(&self) -> IndexIter {
+        self.iter_keys(&[], None)
+    }
+
+    /// Iterates over the entry for `prefix` and all entries below it.
+    pub fn iter_prefix<P: AsRef<Path> + Debug>(&self, prefix: P) -> Result<IndexIter, Error> {
+        let start = encode_key(&prefix)?;
+        if start.is_empty() {
+            return Ok(self.iter());
+        }
+
+        // All keys below the prefix continue with the separator so the first
+        // key that is not part of the prefix continues with the next byte.
+        let mut end = start.clone();
+        end.push(KEY_SEPARATOR + 1);
+
+        Ok(self.iter_keys(&start, Some(end)))
+    }
+
+    fn iter_keys(&self, start: &[u8], end: Option<Vec<u8>>) -> IndexIter {
+        IndexIter {
+            inner: self.db
+                .iterator(IteratorMode::From(start, Direction::Forward)),
+            end: end,
+        }
+    }
+
     pub fn entries(&self) -> Result<BTreeMap<PathBuf, RepoFile>, Error> {
+        self.iter().collect()
+    }
+
+    /// Returns the entry for `prefix` and all entries below it.
+    pub fn entries_with_prefix<P: AsRef<Path> + Debug>(&self, prefix: P) -> Result<BTreeMap<PathBuf, RepoFile>, Error> {
+        self.iter_prefix(prefix)?.collect()
+    }
+
+    /// Like `entries` but does not stop at the first entry that can not be
+    /// decoded. The raw keys of those entries are returned separately so they
+    /// can be reported or removed.
+    pub fn checked_entries(&self) -> (BTreeMap<PathBuf, RepoFile>, Vec<Vec<u8>>) {
         let iter = self.db.iterator(IteratorMode::Start);
 
         let mut out = BTreeMap::default();
-        for (path, metadata) in iter {
-            let decoded_path: PathBuf = deserialize(&path)?;
-            let decoded_metadata: RepoFile = deserialize(&metadata)?;
+        let mut undecodable = Vec::new();
+        for (key, data) in iter {
+            match decode_file(&data) {
+                Ok(file) => {
+                    out.insert(decode_key(&key), file);
+                }
+                Err(_) => undecodable.push(key.to_vec()),
+            }
+        }
+
+        (out, undecodable)
+    }
+}
+
+/// Encodes a path relative to the repository root as index key by joining
+/// its components with `KEY_SEPARATOR`. Paths with other components than
+/// plain names, like `..` or a leading `/`, are rejected as dropping them
+/// would map different paths to the same key.
+fn encode_key<P: AsRef<Path> + Debug>(path: P) -> Result<Vec<u8>, Error> {
+    let mut key = Vec::new();
+
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => {
+                if !key.is_empty() {
+                    key.push(KEY_SEPARATOR);
+                }
 
-            out.insert(decoded_path, decoded_metadata);
+                key.extend_from_slice(name.as_bytes());
+            }
+            _ => bail!("path {:?} is not a normalized path relative to the repository root", path),
         }
+    }
 
-        Ok(out)
+    Ok(key)
+}
+
+fn migrate_entry(legacy: LegacyEntry, file_path: PathBuf) -> RepoFile {
+    let mut file = RepoFile {
+        accessed: legacy.modified,
+        allocated: legacy.len,
+        changed: UNIX_EPOCH,
+        device: 0,
+        gid: 0,
+        group: None,
+        hash: legacy.hash,
+        inode: 0,
+        is_dir: legacy.is_dir,
+        is_symlink: legacy.is_symlink,
+        last_verified: None,
+        len: legacy.len,
+        links: 1,
+        modified: legacy.modified,
+        owner: None,
+        permissions: legacy.permissions,
+        present: true,
+        symlink_target: None,
+        uid: 0,
+        uuid: Uuid::parse_str(&legacy.uuid).unwrap_or_else(|_| Uuid::new_v4()),
+        xattrs: BTreeMap::default(),
+    };
+
+    if let Ok(metadata) = symlink_metadata(&file_path) {
+        file.allocated = sparse::allocated_bytes(&metadata);
+        file.device = metadata.dev();
+        file.gid = metadata.gid();
+        file.inode = metadata.ino();
+        file.links = metadata.nlink();
+        file.uid = metadata.uid();
+    }
+
+    if file.is_symlink {
+        file.symlink_target = read_link(&file_path)
+            .ok()
+            .map(|target| target.into_os_string().into_vec());
+    }
+
+    file
+}
+
+fn decode_key(key: &[u8]) -> PathBuf {
+    let mut path = PathBuf::new();
+
+    if key.is_empty() {
+        return path;
+    }
+
+    for name in key.split(|byte| *byte == KEY_SEPARATOR) {
+        path.push(OsStr::from_bytes(name));
+    }
+
+    path
+}
+
+fn hash_key(hash: &str, key: &[u8]) -> Vec<u8> {
+    let mut out = hash.as_bytes().to_vec();
+    out.push(KEY_SEPARATOR);
+    out.extend_from_slice(key);
+
+    out
+}
+
+fn split_hash_key(key: &[u8]) -> Result<(String, PathBuf), Error> {
+    let position = key.iter()
+        .position(|byte| *byte == KEY_SEPARATOR)
+        .ok_or_else(|| format_err!("hash index key {:?} has no separator", key))?;
+
+    let hash = String::from_utf8(key[..position].to_vec()).context("hash in hash index key is not valid utf8")?;
+
+    Ok((hash, decode_key(&key[position + 1..])))
+}
+
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut out = key.to_vec();
+    out.push(KEY_SEPARATOR);
+    out.push(KEY_SEPARATOR);
+
+    out
+}
+
+fn version_key(key: &[u8], sequence: u64) -> Vec<u8> {
+    let mut out = history_prefix(key);
+    out.extend_from_slice(&sequence_key(sequence));
+
+    out
+}
+
+fn encode_file(file: &RepoFile) -> Result<Vec<u8>, Error> {
+    let data = serialize(&StoredFile::from(file), Infinite).context("can not serialize file struct to bytes")?;
+
+    Ok(data)
+}
+
+fn decode_file(data: &[u8]) -> Result<RepoFile, Error> {
+    let stored: StoredFile = deserialize(data)?;
+
+    Ok(RepoFile::from(stored))
+}
+
+fn decode_version(data: &[u8]) -> Result<FileVersion, Error> {
+    let stored: StoredVersion = deserialize(data)?;
+
+    Ok(FileVersion::from(stored))
+}
+
+fn decode_journal_entry(key: &[u8], data: &[u8]) -> Result<JournalEntry, Error> {
+    let sequence = decode_sequence(key)?;
+    let record: JournalRecord = deserialize(data).map_err(|err| format_err!("can not decode journal entry {}: {}", sequence, err))?;
+
+    let change = match record.file {
+        Some(file) => JournalChange::Set(RepoFile::from(file)),
+        None => JournalChange::Delete,
+    };
+
+    Ok(JournalEntry {
+        sequence: sequence,
+        origin: record.origin,
+        time: record.time,
+        path: decode_key(&record.key),
+        change: change,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_round_trip() {
+        for path in &["a", "photos/2018/a.jpg", "with space/ünïcode"] {
+            let key = encode_key(path).unwrap();
+
+            assert_eq!(decode_key(&key), PathBuf::from(path));
+        }
+    }
+
+    #[test]
+    fn key_round_trip_non_utf8() {
+        let path = PathBuf::from(OsStr::from_bytes(b"dir/\xff\xfe"));
+        let key = encode_key(&path).unwrap();
+
+        assert_eq!(decode_key(&key), path);
+    }
+
+    #[test]
+    fn keys_sort_like_paths() {
+        let mut paths = vec!["a.b", "a/b/c", "b", "a", "a/b", "a-b", "a/b.c"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let keys = paths.iter().map(|path| encode_key(path).unwrap()).collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn current_dir_components_are_ignored() {
+        assert_eq!(encode_key("a/./b").unwrap(), encode_key("a/b").unwrap());
+    }
+
+    #[test]
+    fn keys_reject_non_normal_components() {
+        for path in &["/a", "../a", "a/../b"] {
+            assert!(encode_key(path).is_err(), "{}", path);
+        }
     }
 }
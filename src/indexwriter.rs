@@ -0,0 +1,75 @@
+use crossbeam_channel::{
+    unbounded,
+    Sender,
+};
+use failure::Error;
+use index::{
+    Index,
+    IndexUpdate,
+};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+
+/// How many updates get collected before they are written to the index.
+const BATCH_SIZE: usize = 1000;
+
+/// Writes index updates from multiple threads in batches. Every message sent
+/// to the writer is applied atomically and never split across batches, so
+/// updates that belong together (like the two halves of a move) either both
+/// end up in the index or none of them do.
+pub struct IndexWriter {
+    tx: Sender<Vec<IndexUpdate>>,
+    handle: JoinHandle<Result<usize, Error>>,
+}
+
+impl IndexWriter {
+    pub fn new(index: Arc<Index>) -> IndexWriter {
+        let (tx, rx) = unbounded::<Vec<IndexUpdate>>();
+
+        let handle = thread::spawn(move || {
+            let mut pending = Vec::new();
+            let mut written = 0;
+
+            while let Ok(updates) = rx.recv() {
+                pending.extend(updates);
+
+                if pending.len() >= BATCH_SIZE {
+                    written += pending.len();
+                    debug!("index writer: writing batch of {} updates", pending.len());
+                    index.write(pending)?;
+                    pending = Vec::new();
+                }
+            }
+
+            if !pending.is_empty() {
+                written += pending.len();
+                debug!("index writer: writing last batch of {} updates", pending.len());
+                index.write(pending)?;
+            }
+
+            Ok(written)
+        });
+
+        IndexWriter {
+            tx: tx,
+            handle: handle,
+        }
+    }
+
+    pub fn sender(&self) -> Sender<Vec<IndexUpdate>> {
+        self.tx.clone()
+    }
+
+    /// Waits until all updates that were sent are written. All senders
+    /// returned by `sender` have to be dropped before calling this. Returns
+    /// how many updates were written.
+    pub fn finish(self) -> Result<usize, Error> {
+        drop(self.tx);
+
+        match self.handle.join() {
+            Ok(result) => result,
+            Err(_) => bail!("index writer thread panicked"),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use failure::Error;
+use output::serialize_path;
+use repofile::{
+    RepoFile,
+    StoredFile,
+};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use uuid::Uuid;
+use uuidbytes;
+
+/// How a journal entry is stored. The path is kept as index key because
+/// bincode can not encode paths that are not valid UTF-8. A missing file
+/// means the path was deleted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalRecord {
+    #[serde(with = "uuidbytes")]
+    pub origin: Uuid,
+    pub time: SystemTime,
+    pub key: Vec<u8>,
+    pub file: Option<StoredFile>,
+}
+
+#[derive(Serialize, Debug)]
+pub enum JournalChange {
+    Set(RepoFile),
+    Delete,
+}
+
+/// One mutation of the index. Sequence numbers are assigned by the
+/// repository that recorded the change and only ever increase, so a peer
+/// that has seen everything up to a sequence number only needs the entries
+/// after it.
+#[derive(Serialize, Debug)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    /// UUID of the repository the change was made in.
+    pub origin: Uuid,
+    pub time: SystemTime,
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    pub change: JournalChange,
+}
+
+impl fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.change {
+            JournalChange::Set(ref file) => write!(
+                f,
+                "{}\t{}\tset\t{:?}\t{}",
+                self.sequence,
+                self.origin,
+                self.path,
+                file.hash.as_ref().map(|hash| hash.as_str()).unwrap_or("-")
+            ),
+            JournalChange::Delete => write!(f, "{}\t{}\tdelete\t{:?}", self.sequence, self.origin, self.path),
+        }
+    }
+}
+
+/// Journal keys are the sequence number in big endian so RocksDB keeps the
+/// entries in sequence order.
+pub fn sequence_key(sequence: u64) -> [u8; 8] {
+    let mut key = [0; 8];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = (sequence >> (56 - index * 8)) as u8;
+    }
+
+    key
+}
+
+pub fn decode_sequence(key: &[u8]) -> Result<u64, Error> {
+    if key.len() != 8 {
+        bail!("journal key {:?} is not a sequence number", key)
+    }
+
+    Ok(key.iter().fold(0, |acc, byte| (acc << 8) | u64::from(*byte)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_round_trip() {
+        for sequence in &[0, 1, 255, 256, 1 << 40, u64::max_value()] {
+            assert_eq!(decode_sequence(&sequence_key(*sequence)).unwrap(), *sequence);
+        }
+    }
+
+    #[test]
+    fn sequence_keys_sort_like_numbers() {
+        let sequences = [0, 1, 2, 255, 256, 65_535, 65_536, 1 << 40, u64::max_value()];
+        let keys = sequences.iter().map(|sequence| sequence_key(*sequence)).collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn decode_rejects_wrong_length() {
+        assert!(decode_sequence(&[0; 7]).is_err());
+        assert!(decode_sequence(&[0; 9]).is_err());
+    }
+}
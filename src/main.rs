@@ -4,6 +4,9 @@ extern crate clap;
 extern crate crossbeam_channel;
 #[macro_use]
 extern crate failure;
+extern crate globset;
+extern crate ignore;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate num_cpus;
@@ -18,24 +21,62 @@ extern crate time;
 extern crate uuid;
 extern crate walkdir;
 
+mod addreport;
+mod attributes;
+mod duplicates;
+mod fsck;
+mod gc;
+mod hardlinks;
+mod history;
+mod ignorefilter;
 mod index;
+mod indexwriter;
+mod journal;
+mod movecandidates;
+mod output;
 mod pathclassifier;
+mod pathspec;
+mod progress;
 mod repofile;
 mod repository;
 mod repostatus;
+mod scheduler;
+mod snapshot;
+mod sparse;
+#[cfg(test)]
+mod testdir;
+mod transfer;
+mod uuidbytes;
 
 use failure::{
     Context,
     Error,
     ResultExt,
 };
+use fsck::{
+    FsckOptions,
+    ScrubOptions,
+};
+use gc::GcOptions;
+use history::RestoreVersion;
+use output::OutputFormat;
+use progress::ProgressMode;
 use repository::Repository;
+use scheduler::TransferPriority;
 use simplelog::*;
 use std::path::PathBuf;
+use std::time::{
+    Duration,
+    UNIX_EPOCH,
+};
 
 #[derive(Debug, Fail)]
 enum CliError {
+    #[fail(display = "can not add {} paths", _0)] AddFailed(usize),
     #[fail(display = "can not get repo_path from matches")] CanNotGetRepoPathFromMatches,
+    #[fail(display = "repository check found problems")] FsckFoundProblems,
+    #[fail(display = "snapshot could not be restored completely")] SnapshotIncomplete,
+    #[fail(display = "status could not check {} paths", _0)] StatusIncomplete(usize),
 }
 
 fn main() {
@@ -65,16 +106,28 @@ fn run() -> Result<(), Error> {
     )?;
     trace!("main::run: matches - {:#?}", matches);
 
+    let output = value_t!(matches, "output", OutputFormat)?;
+    let progress = value_t!(matches, "progress", ProgressMode)?;
+
     match matches.subcommand_name() {
         Some("add_remote") => run_add_remote(matches.subcommand_matches("add_remote").unwrap())?,
-        Some("add") => run_add(matches.subcommand_matches("add").unwrap())?,
+        Some("add") => run_add(matches.subcommand_matches("add").unwrap(), output, progress)?,
         Some("clone") => run_clone(matches.subcommand_matches("clone").unwrap())?,
-        Some("debug") => run_debug(matches.subcommand_matches("debug").unwrap())?,
+        Some("debug") => run_debug(matches.subcommand_matches("debug").unwrap(), output)?,
         Some("drop") => run_drop(matches.subcommand_matches("drop").unwrap())?,
+        Some("duplicates") => run_duplicates(matches.subcommand_matches("duplicates").unwrap(), output)?,
+        Some("fsck") => run_fsck(matches.subcommand_matches("fsck").unwrap(), output, progress)?,
+        Some("gc") => run_gc(matches.subcommand_matches("gc").unwrap(), output)?,
         Some("get") => run_get(matches.subcommand_matches("get").unwrap())?,
         Some("init") => run_init(matches.subcommand_matches("init").unwrap())?,
+        Some("log") => run_log(matches.subcommand_matches("log").unwrap(), output)?,
+        Some("mv") => run_mv(matches.subcommand_matches("mv").unwrap())?,
         Some("remote") => run_remote(matches.subcommand_matches("remote").unwrap())?,
-        Some("status") => run_status(matches.subcommand_matches("status").unwrap())?,
+        Some("restore") => run_restore(matches.subcommand_matches("restore").unwrap())?,
+        Some("rm") => run_rm(matches.subcommand_matches("rm").unwrap())?,
+        Some("scrub") => run_scrub(matches.subcommand_matches("scrub").unwrap(), output)?,
+        Some("snapshot") => run_snapshot(matches.subcommand_matches("snapshot").unwrap(), output)?,
+        Some("status") => run_status(matches.subcommand_matches("status").unwrap(), output, progress)?,
         Some("sync") => run_sync(matches.subcommand_matches("sync").unwrap())?,
         Some("type") => run_type(matches.subcommand_matches("type").unwrap())?,
         Some("watch") => run_watch(matches.subcommand_matches("watch").unwrap())?,
@@ -88,7 +141,7 @@ fn run_add_remote(_matches: &clap::ArgMatches) -> Result<(), Error> {
     unimplemented!()
 }
 
-fn run_add(matches: &clap::ArgMatches) -> Result<(), Error> {
+fn run_add(matches: &clap::ArgMatches, output: OutputFormat, progress: ProgressMode) -> Result<(), Error> {
     let repo_path: PathBuf = matches
         .value_of("repo_path")
         .ok_or(CliError::CanNotGetRepoPathFromMatches)?
@@ -96,11 +149,19 @@ fn run_add(matches: &clap::ArgMatches) -> Result<(), Error> {
 
     let paths_to_add = values_t!(matches.values_of("paths_to_add"), String).context("can not get paths to add from matches")?;
 
-    let mut repo = Repository::open(repo_path).context("can not open repository")?;
+    let mut repo = Repository::open(repo_path)
+        .context("can not open repository")?
+        .with_progress(progress.listener());
 
-    repo.add(paths_to_add)
+    let report = repo.add(paths_to_add)
         .context("can not add files to repository")?;
 
+    output::print(output, &report)?;
+
+    if report.failed_count > 0 {
+        Err(CliError::AddFailed(report.failed_count))?
+    }
+
     Ok(())
 }
 
@@ -147,7 +208,7 @@ fn run_clone(matches: &clap::ArgMatches) -> Result<(), Error> {
     Ok(())
 }
 
-fn run_debug(matches: &clap::ArgMatches) -> Result<(), Error> {
+fn run_debug(matches: &clap::ArgMatches, output: OutputFormat) -> Result<(), Error> {
     let subcommand = matches.subcommand_name().unwrap();
     let matches = matches.subcommand_matches(subcommand).unwrap();
 
@@ -159,7 +220,12 @@ fn run_debug(matches: &clap::ArgMatches) -> Result<(), Error> {
     let repo = Repository::open(repo_path).context("can not open repository")?;
 
     match subcommand {
-        "tracked_files" => repo.debug_tracked_files()?,
+        "tracked_files" => repo.debug_tracked_files(output)?,
+        "journal" => repo.debug_journal(
+            value_t!(matches, "since", u64)?,
+            matches.value_of("remote"),
+            output,
+        )?,
         _ => unreachable!(),
     }
 
@@ -170,6 +236,67 @@ fn run_drop(_matches: &clap::ArgMatches) -> Result<(), Error> {
     unimplemented!()
 }
 
+fn run_duplicates(matches: &clap::ArgMatches, output: OutputFormat) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+    let report = repo.duplicates()
+        .context("can not find duplicates in repository")?;
+
+    output::print(output, &report)?;
+
+    Ok(())
+}
+
+fn run_fsck(matches: &clap::ArgMatches, output: OutputFormat, progress: ProgressMode) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let options = FsckOptions {
+        fast: matches.is_present("fast"),
+        repair: matches.is_present("repair"),
+        repair_from_duplicates: matches.is_present("repair_from_duplicates"),
+        resume: matches.is_present("resume"),
+    };
+
+    let repo = Repository::open(repo_path)
+        .context("can not open repository")?
+        .with_progress(progress.listener());
+    let report = repo.fsck(&options).context("can not check repository")?;
+
+    output::print(output, &report)?;
+
+    if !report.is_clean() {
+        Err(CliError::FsckFoundProblems)?
+    }
+
+    Ok(())
+}
+
+fn run_gc(matches: &clap::ArgMatches, output: OutputFormat) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let options = GcOptions {
+        dry_run: matches.is_present("dry_run"),
+        grace_period: Duration::from_secs(value_t!(matches, "grace_period", u64)?),
+    };
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+    let report = repo.gc(&options).context("can not collect garbage")?;
+
+    output::print(output, &report)?;
+
+    Ok(())
+}
+
 fn run_get(_matches: &clap::ArgMatches) -> Result<(), Error> {
     unimplemented!()
 }
@@ -188,20 +315,214 @@ fn run_init(matches: &clap::ArgMatches) -> Result<(), Error> {
     Ok(())
 }
 
+fn run_log(matches: &clap::ArgMatches, output: OutputFormat) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let path: PathBuf = matches
+        .value_of("path")
+        .ok_or_else(|| Context::new("can not get path from matches"))?
+        .into();
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+    let history = repo.history(path)
+        .context("can not get history of path")?;
+
+    output::print(output, &history)?;
+
+    Ok(())
+}
+
+fn run_mv(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let source_path: PathBuf = matches
+        .value_of("source_path")
+        .ok_or_else(|| Context::new("can not get source_path from matches"))?
+        .into();
+
+    let destination_path: PathBuf = matches
+        .value_of("destination_path")
+        .ok_or_else(|| Context::new("can not get destination_path from matches"))?
+        .into();
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+
+    repo.rename(source_path, destination_path)
+        .context("can not move path in repository")?;
+
+    Ok(())
+}
+
 fn run_remote(_matches: &clap::ArgMatches) -> Result<(), Error> {
     unimplemented!()
 }
 
-fn run_status(matches: &clap::ArgMatches) -> Result<(), Error> {
+fn run_restore(matches: &clap::ArgMatches) -> Result<(), Error> {
     let repo_path: PathBuf = matches
         .value_of("repo_path")
         .ok_or(CliError::CanNotGetRepoPathFromMatches)?
         .into();
 
-    let repo = Repository::default().with_path(repo_path);
-    let status = repo.status().context("can not get status from repo")?;
+    let path: PathBuf = matches
+        .value_of("path")
+        .ok_or_else(|| Context::new("can not get path from matches"))?
+        .into();
+
+    let version = if matches.is_present("version") {
+        RestoreVersion::Sequence(value_t!(matches, "version", u64)?)
+    } else {
+        RestoreVersion::At(UNIX_EPOCH + Duration::from_secs(value_t!(matches, "at", u64)?))
+    };
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+
+    repo.restore(path, &version)
+        .context("can not restore file")?;
+
+    Ok(())
+}
+
+fn run_rm(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let paths_to_remove = values_t!(matches.values_of("paths_to_remove"), String).context("can not get paths to remove from matches")?;
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+
+    repo.remove(paths_to_remove, matches.is_present("cached"))
+        .context("can not remove files from repository")?;
+
+    Ok(())
+}
+
+fn run_scrub(matches: &clap::ArgMatches, output: OutputFormat) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let max_duration = if matches.is_present("max_time") {
+        Some(Duration::from_secs(value_t!(matches, "max_time", u64)?))
+    } else {
+        None
+    };
+
+    let max_bytes = if matches.is_present("max_bytes") {
+        Some(value_t!(matches, "max_bytes", u64)?)
+    } else {
+        None
+    };
+
+    let options = ScrubOptions {
+        max_duration: max_duration,
+        max_bytes: max_bytes,
+    };
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+    let report = repo.scrub(&options).context("can not scrub repository")?;
 
-    println!("{}", status);
+    output::print(output, &report)?;
+
+    if !report.is_clean() {
+        Err(CliError::FsckFoundProblems)?
+    }
+
+    Ok(())
+}
+
+fn run_snapshot(matches: &clap::ArgMatches, output: OutputFormat) -> Result<(), Error> {
+    let subcommand = matches.subcommand_name().unwrap();
+    let matches = matches.subcommand_matches(subcommand).unwrap();
+
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let repo = Repository::open(repo_path).context("can not open repository")?;
+
+    match subcommand {
+        "create" => repo.snapshot_create(matches.value_of("name").unwrap())
+            .context("can not create snapshot")?,
+        "delete" => repo.snapshot_delete(matches.value_of("name").unwrap())
+            .context("can not delete snapshot")?,
+        "list" => {
+            let list = repo.snapshot_list().context("can not list snapshots")?;
+            output::print(output, &list)?
+        }
+        "restore" => {
+            let mut options = repo.scheduler_options();
+            if matches.is_present("concurrency") {
+                options.concurrency = value_t!(matches, "concurrency", usize)?;
+            }
+            if matches.is_present("bandwidth_limit") {
+                options.bandwidth_limit = Some(value_t!(matches, "bandwidth_limit", u64)?);
+                scheduler::check_bandwidth_limit("bandwidth_limit", options.bandwidth_limit)?;
+            }
+            if matches.is_present("priority") {
+                options.priority = value_t!(matches, "priority", TransferPriority)?;
+            }
+            if matches.is_present("retries") {
+                options.retries = value_t!(matches, "retries", u32)?;
+            }
+
+            let report = repo.snapshot_restore(
+                matches.value_of("name").unwrap(),
+                matches.value_of("destination_path").unwrap(),
+                &options,
+            ).context("can not restore snapshot")?;
+
+            output::print(output, &report)?;
+
+            if !report.is_complete() {
+                Err(CliError::SnapshotIncomplete)?
+            }
+        }
+        "show" => {
+            let view = repo.snapshot_show(matches.value_of("name").unwrap())
+                .context("can not show snapshot")?;
+            output::print(output, &view)?
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn run_status(matches: &clap::ArgMatches, output: OutputFormat, progress: ProgressMode) -> Result<(), Error> {
+    let repo_path: PathBuf = matches
+        .value_of("repo_path")
+        .ok_or(CliError::CanNotGetRepoPathFromMatches)?
+        .into();
+
+    let pathspecs = if matches.is_present("pathspecs") {
+        values_t!(matches.values_of("pathspecs"), String).context("can not get pathspecs from matches")?
+    } else {
+        Vec::new()
+    };
+
+    let repo = Repository::open(repo_path)
+        .context("can not open repository")?
+        .with_progress(progress.listener());
+    let pathspec = repo.pathspec(&pathspecs)
+        .context("can not parse pathspecs")?;
+    let status = repo.status(&pathspec)
+        .context("can not get status from repo")?;
+
+    output::print(output, &status)?;
+
+    if !status.errors.is_empty() {
+        Err(CliError::StatusIncomplete(status.errors.len()))?
+    }
 
     Ok(())
 }
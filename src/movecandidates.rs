@@ -0,0 +1,45 @@
+use repofile::RepoFile;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Tracked files that are no longer on disk. When a new file shows up that
+/// matches one of them we treat it as a move instead of a delete and add.
+#[derive(Default)]
+pub struct MoveCandidates {
+    by_len: Mutex<BTreeMap<u64, Vec<(PathBuf, RepoFile)>>>,
+}
+
+impl MoveCandidates {
+    pub fn new(entries: Vec<(PathBuf, RepoFile)>) -> MoveCandidates {
+        let mut by_len: BTreeMap<u64, Vec<(PathBuf, RepoFile)>> = BTreeMap::default();
+
+        for (path, entry) in entries {
+            // Symlinks are cheap to recreate so they are not worth tracking
+            // as moves.
+            if entry.is_dir || entry.is_symlink {
+                continue;
+            }
+
+            by_len.entry(entry.len).or_insert_with(Vec::new).push((path, entry));
+        }
+
+        MoveCandidates {
+            by_len: Mutex::new(by_len),
+        }
+    }
+
+    /// Takes a candidate with the same content. Length and modified time
+    /// are not enough to tell, another file can share them by chance and a
+    /// deleted file's inode is reused right away.
+    pub fn take_by_hash(&self, len: u64, hash: &str) -> Option<(PathBuf, RepoFile)> {
+        let mut by_len = self.by_len.lock().unwrap();
+        let candidates = by_len.get_mut(&len)?;
+
+        let position = candidates
+            .iter()
+            .position(|&(_, ref entry)| entry.hash.as_ref().map(String::as_str) == Some(hash))?;
+
+        Some(candidates.remove(position))
+    }
+}
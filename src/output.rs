@@ -0,0 +1,69 @@
+use failure::Error;
+use serde::{
+    Serialize,
+    Serializer,
+};
+use serde_json;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat, Error> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown output format {}", s),
+        }
+    }
+}
+
+pub fn print<T: Serialize + Display>(format: OutputFormat, value: &T) -> Result<(), Error> {
+    match format {
+        OutputFormat::Text => println!("{}", value),
+        OutputFormat::Json => print_json(value)?,
+    }
+
+    Ok(())
+}
+
+pub fn print_json<T: Serialize>(value: &T) -> Result<(), Error> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+/// Paths are not guaranteed to be valid UTF-8. Those that are get serialized
+/// as a string, all others as an array of their raw bytes so no information
+/// gets lost.
+pub struct RawPath<'a>(pub &'a Path);
+
+impl<'a> Serialize for RawPath<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.collect_seq(self.0.as_os_str().as_bytes()),
+        }
+    }
+}
+
+pub fn serialize_path<S: Serializer>(path: &PathBuf, serializer: S) -> Result<S::Ok, S::Error> {
+    RawPath(path).serialize(serializer)
+}
+
+pub fn serialize_paths<S: Serializer>(paths: &BTreeSet<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| RawPath(path)))
+}
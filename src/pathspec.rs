@@ -0,0 +1,234 @@
+use failure::{
+    Error,
+    ResultExt,
+};
+use globset::{
+    GlobBuilder,
+    GlobMatcher,
+};
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
+/// Characters that turn a pathspec into a glob instead of a plain path.
+const GLOB_CHARACTERS: &[char] = &['*', '?', '[', '{'];
+
+#[derive(Debug)]
+enum Pattern {
+    /// Matches the path itself and everything below it.
+    Prefix(PathBuf),
+    Glob {
+        /// The leading components of the glob that do not contain any glob
+        /// characters. Only paths below this can match.
+        base: PathBuf,
+        matcher: GlobMatcher,
+    },
+}
+
+impl Pattern {
+    fn new(root: &Path, pattern: &Path) -> Result<Pattern, Error> {
+        // `./photos` and `photos` select the same paths.
+        let pattern = pattern
+            .strip_prefix(root)
+            .unwrap_or(pattern)
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>();
+        let pattern = pattern.as_path();
+
+        let pattern_string = pattern
+            .to_str()
+            .ok_or_else(|| format_err!("can not convert pathspec {:?} to string", pattern))?;
+
+        if !pattern_string.contains(GLOB_CHARACTERS) {
+            return Ok(Pattern::Prefix(pattern.to_path_buf()));
+        }
+
+        let base = pattern
+            .components()
+            .take_while(|component| match *component {
+                Component::Normal(name) => !name.to_string_lossy().contains(GLOB_CHARACTERS),
+                _ => true,
+            })
+            .collect();
+
+        let matcher = GlobBuilder::new(pattern_string)
+            .literal_separator(true)
+            .build()
+            .context(format_err!("can not parse glob {}", pattern_string))?
+            .compile_matcher();
+
+        Ok(Pattern::Glob {
+            base: base,
+            matcher: matcher,
+        })
+    }
+
+    fn base(&self) -> &Path {
+        match *self {
+            Pattern::Prefix(ref path) => path,
+            Pattern::Glob { ref base, .. } => base,
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        match *self {
+            Pattern::Prefix(ref prefix) => path.starts_with(prefix),
+            Pattern::Glob { ref matcher, .. } => matcher.is_match(path),
+        }
+    }
+}
+
+/// Selects a subset of the paths in the repository. Pathspecs are relative to
+/// the repository root (a leading repository path is stripped) and can be
+/// plain paths (matching the path and everything below it), globs like
+/// `**/*.raw` and exclusions prefixed with `!` or `:!`. An empty pathspec
+/// matches everything.
+#[derive(Debug, Default)]
+pub struct Pathspec {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+}
+
+impl Pathspec {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(root: P, patterns: &[Q]) -> Result<Pathspec, Error> {
+        let root = root.as_ref();
+        let mut pathspec = Pathspec::default();
+
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let pattern_string = pattern.to_string_lossy();
+
+            if pattern_string.starts_with(":!") {
+                pathspec
+                    .excludes
+                    .push(Pattern::new(root, Path::new(&pattern_string[2..]))?);
+            } else if pattern_string.starts_with('!') {
+                pathspec
+                    .excludes
+                    .push(Pattern::new(root, Path::new(&pattern_string[1..]))?);
+            } else {
+                pathspec.includes.push(Pattern::new(root, pattern)?);
+            }
+        }
+
+        Ok(pathspec)
+    }
+
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+
+        let included = self.includes.is_empty() || self.includes.iter().any(|pattern| pattern.matches(path));
+
+        included && !self.excludes.iter().any(|pattern| pattern.matches(path))
+    }
+
+    /// Directories that contain all paths that can match. Walking or scanning
+    /// only these is enough to find every match. Bases that are below another
+    /// base are left out.
+    pub fn bases(&self) -> Vec<PathBuf> {
+        if self.includes.is_empty() {
+            return vec![PathBuf::new()];
+        }
+
+        let mut bases = self.includes
+            .iter()
+            .map(|pattern| pattern.base().to_path_buf())
+            .collect::<Vec<_>>();
+
+        bases.sort();
+
+        let mut out: Vec<PathBuf> = Vec::new();
+        for base in bases {
+            if out.iter().any(|existing| base.starts_with(existing)) {
+                continue;
+            }
+
+            out.push(base);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pathspec(patterns: &[&str]) -> Pathspec {
+        Pathspec::new("/repo", patterns).unwrap()
+    }
+
+    #[test]
+    fn empty_matches_everything() {
+        let pathspec = pathspec(&[]);
+
+        assert!(pathspec.matches("photos/a.jpg"));
+        assert_eq!(pathspec.bases(), vec![PathBuf::new()]);
+    }
+
+    #[test]
+    fn plain_path_matches_itself_and_below() {
+        let pathspec = pathspec(&["photos"]);
+
+        assert!(pathspec.matches("photos"));
+        assert!(pathspec.matches("photos/2018/a.jpg"));
+        assert!(!pathspec.matches("photos2/a.jpg"));
+        assert!(!pathspec.matches("music/a.mp3"));
+    }
+
+    #[test]
+    fn root_and_current_dir_are_stripped() {
+        for pattern in &["/repo/photos", "./photos", "photos/."] {
+            let pathspec = pathspec(&[pattern]);
+
+            assert!(pathspec.matches("photos/a.jpg"), "{}", pattern);
+            assert_eq!(pathspec.bases(), vec![PathBuf::from("photos")], "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn glob_does_not_cross_separators() {
+        let pathspec = pathspec(&["photos/*.jpg"]);
+
+        assert!(pathspec.matches("photos/a.jpg"));
+        assert!(!pathspec.matches("photos/2018/a.jpg"));
+        assert_eq!(pathspec.bases(), vec![PathBuf::from("photos")]);
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        let pathspec = pathspec(&["**/*.raw"]);
+
+        assert!(pathspec.matches("a.raw"));
+        assert!(pathspec.matches("photos/2018/a.raw"));
+        assert!(!pathspec.matches("photos/a.jpg"));
+    }
+
+    #[test]
+    fn exclusions_win_over_includes() {
+        for exclude in &["!photos/tmp", ":!photos/tmp"] {
+            let pathspec = pathspec(&["photos", exclude]);
+
+            assert!(pathspec.matches("photos/a.jpg"));
+            assert!(!pathspec.matches("photos/tmp/a.jpg"));
+        }
+    }
+
+    #[test]
+    fn only_exclusions_match_everything_else() {
+        let pathspec = pathspec(&["!*.tmp"]);
+
+        assert!(pathspec.matches("a.jpg"));
+        assert!(!pathspec.matches("a.tmp"));
+    }
+
+    #[test]
+    fn nested_bases_are_merged() {
+        let pathspec = pathspec(&["photos/2018", "photos", "music/**/*.mp3"]);
+
+        assert_eq!(pathspec.bases(), vec![PathBuf::from("music"), PathBuf::from("photos")]);
+    }
+}
@@ -0,0 +1,307 @@
+use failure::Error;
+use libc;
+use std::fmt;
+use std::io::{
+    stderr,
+    Write,
+};
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+/// How often the terminal line is redrawn.
+const TERMINAL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a progress line is logged when stderr is not a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// Terminal output when stderr is a terminal, log lines otherwise.
+    Auto,
+    Terminal,
+    Log,
+    None,
+}
+
+impl FromStr for ProgressMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProgressMode, Error> {
+        match s {
+            "auto" => Ok(ProgressMode::Auto),
+            "terminal" => Ok(ProgressMode::Terminal),
+            "log" => Ok(ProgressMode::Log),
+            "none" => Ok(ProgressMode::None),
+            _ => bail!("unknown progress mode {}", s),
+        }
+    }
+}
+
+impl ProgressMode {
+    /// Returns the listener that prints progress in this mode.
+    pub fn listener(self) -> Option<Arc<dyn ProgressListener>> {
+        match self {
+            ProgressMode::Auto => if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+                ProgressMode::Terminal.listener()
+            } else {
+                ProgressMode::Log.listener()
+            },
+            ProgressMode::Terminal => Some(Arc::new(TerminalProgress)),
+            ProgressMode::Log => Some(Arc::new(LogProgress)),
+            ProgressMode::None => None,
+        }
+    }
+}
+
+/// Counters of a running operation at one point in time. Totals are only
+/// known for operations that know their work upfront, the ETA is only
+/// available when they are.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProgressSnapshot {
+    pub operation: &'static str,
+    pub files: u64,
+    pub bytes: u64,
+    pub total_files: Option<u64>,
+    pub total_bytes: Option<u64>,
+    pub elapsed: Duration,
+    pub bytes_per_second: u64,
+    pub eta: Option<Duration>,
+}
+
+impl fmt::Display for ProgressSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.operation, self.files)?;
+        if let Some(total_files) = self.total_files {
+            write!(f, "/{}", total_files)?;
+        }
+
+        write!(f, " files, {}", format_bytes(self.bytes))?;
+        if let Some(total_bytes) = self.total_bytes {
+            write!(f, "/{}", format_bytes(total_bytes))?;
+        }
+
+        write!(f, ", {}/s", format_bytes(self.bytes_per_second))?;
+
+        if let Some(eta) = self.eta {
+            write!(f, ", ETA {}", format_duration(eta))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub enum ProgressEvent {
+    Started(ProgressSnapshot),
+    Updated(ProgressSnapshot),
+    Finished(ProgressSnapshot),
+}
+
+/// Receives the progress of long running operations. Events are sent from
+/// the threads doing the work so listeners have to be cheap.
+pub trait ProgressListener: fmt::Debug + Send + Sync {
+    fn event(&self, event: &ProgressEvent);
+
+    /// Minimum time between two `Updated` events.
+    fn interval(&self) -> Duration {
+        TERMINAL_INTERVAL
+    }
+}
+
+/// Redraws a single status line on stderr.
+#[derive(Debug)]
+pub struct TerminalProgress;
+
+impl ProgressListener for TerminalProgress {
+    fn event(&self, event: &ProgressEvent) {
+        let mut stderr = stderr();
+
+        // Progress output is best effort, failing to print it should not
+        // stop the operation.
+        let _ = match *event {
+            ProgressEvent::Started(_) => Ok(()),
+            ProgressEvent::Updated(ref snapshot) => write!(stderr, "\r\x1b[K{}", snapshot),
+            ProgressEvent::Finished(ref snapshot) => writeln!(stderr, "\r\x1b[K{}", snapshot),
+        };
+
+        let _ = stderr.flush();
+    }
+}
+
+/// Logs a progress line every now and then, for output that is not a
+/// terminal.
+#[derive(Debug)]
+pub struct LogProgress;
+
+impl ProgressListener for LogProgress {
+    fn event(&self, event: &ProgressEvent) {
+        match *event {
+            ProgressEvent::Started(ref snapshot) => info!("{} started", snapshot.operation),
+            ProgressEvent::Updated(ref snapshot) => info!("{}", snapshot),
+            ProgressEvent::Finished(ref snapshot) => info!("{} finished in {}", snapshot, format_duration(snapshot.elapsed)),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        LOG_INTERVAL
+    }
+}
+
+/// Tracks the progress of one operation and forwards it to a listener.
+/// Clones share the counters so it can be handed to worker threads. Without
+/// a listener all calls do nothing.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    tracker: Option<Arc<Tracker>>,
+}
+
+#[derive(Debug)]
+struct Tracker {
+    operation: &'static str,
+    listener: Arc<dyn ProgressListener>,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    files: u64,
+    bytes: u64,
+    total_files: Option<u64>,
+    total_bytes: Option<u64>,
+    last_event: Instant,
+}
+
+impl Progress {
+    pub fn new(operation: &'static str, listener: Option<Arc<dyn ProgressListener>>) -> Progress {
+        let tracker = listener.map(|listener| {
+            let now = Instant::now();
+
+            let tracker = Tracker {
+                operation: operation,
+                listener: listener,
+                started: now,
+                state: Mutex::new(State {
+                    files: 0,
+                    bytes: 0,
+                    total_files: None,
+                    total_bytes: None,
+                    last_event: now,
+                }),
+            };
+
+            let snapshot = tracker.snapshot(&tracker.state.lock().expect("progress lock is poisoned"));
+            tracker.listener.event(&ProgressEvent::Started(snapshot));
+
+            Arc::new(tracker)
+        });
+
+        Progress { tracker: tracker }
+    }
+
+    /// Sets the amount of work the operation is going to do. Enables the ETA.
+    pub fn set_total(&self, files: u64, bytes: u64) {
+        if let Some(ref tracker) = self.tracker {
+            let mut state = tracker.state.lock().expect("progress lock is poisoned");
+            state.total_files = Some(files);
+            state.total_bytes = Some(bytes);
+        }
+    }
+
+    /// Records processed files and bytes and sends an update when the
+    /// interval of the listener has passed since the last one.
+    pub fn advance(&self, files: u64, bytes: u64) {
+        if let Some(ref tracker) = self.tracker {
+            let mut state = tracker.state.lock().expect("progress lock is poisoned");
+            state.files += files;
+            state.bytes += bytes;
+
+            if state.last_event.elapsed() < tracker.listener.interval() {
+                return;
+            }
+
+            state.last_event = Instant::now();
+            tracker.listener.event(&ProgressEvent::Updated(tracker.snapshot(&state)));
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Some(ref tracker) = self.tracker {
+            let state = tracker.state.lock().expect("progress lock is poisoned");
+            tracker.listener.event(&ProgressEvent::Finished(tracker.snapshot(&state)));
+        }
+    }
+}
+
+impl Tracker {
+    fn snapshot(&self, state: &State) -> ProgressSnapshot {
+        let elapsed = self.started.elapsed();
+        let seconds = duration_seconds(elapsed);
+
+        let bytes_per_second = if seconds > 0.0 {
+            (state.bytes as f64 / seconds) as u64
+        } else {
+            0
+        };
+
+        // Prefer bytes as files can differ a lot in size and fall back to
+        // files for operations that process little data.
+        let eta = match (state.total_files, state.total_bytes) {
+            (_, Some(total_bytes)) if state.bytes > 0 => Some(remaining(seconds, state.bytes, total_bytes)),
+            (Some(total_files), _) if state.files > 0 => Some(remaining(seconds, state.files, total_files)),
+            _ => None,
+        };
+
+        ProgressSnapshot {
+            operation: self.operation,
+            files: state.files,
+            bytes: state.bytes,
+            total_files: state.total_files,
+            total_bytes: state.total_bytes,
+            elapsed: elapsed,
+            bytes_per_second: bytes_per_second,
+            eta: eta,
+        }
+    }
+}
+
+fn remaining(seconds: f64, done: u64, total: u64) -> Duration {
+    let left = total.saturating_sub(done) as f64;
+    let remaining = seconds / done as f64 * left;
+
+    Duration::from_millis((remaining * 1000.0) as u64)
+}
+
+fn duration_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
@@ -1,3 +1,5 @@
+use attributes;
+use attributes::XattrFilter;
 use failure::{
     Error,
     ResultExt,
@@ -6,34 +8,186 @@ use sha2::{
     Digest,
     Sha256,
 };
+use sparse;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::fs::{
+    read_link,
     symlink_metadata,
     File,
+    Metadata,
+};
+use std::io::Read;
+use std::os::unix::ffi::{
+    OsStrExt,
+    OsStringExt,
+};
+use std::os::unix::fs::{
+    symlink,
+    MetadataExt,
+    PermissionsExt,
 };
-use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use std::time::SystemTime;
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
 use uuid::Uuid;
+use uuidbytes;
+
+/// Result of comparing the cached stat data of an entry with the metadata of
+/// the file on disk.
+#[derive(Debug, PartialEq)]
+pub enum StatMatch {
+    Unchanged,
+    Changed,
+    /// The stat data differs but the content might still be the same so the
+    /// file has to be hashed to find out.
+    Unknown,
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct RepoFile {
+    /// Access time when the entry was recorded, only restored with the
+    /// `restore_atime` setting.
+    pub accessed: SystemTime,
+    /// Bytes allocated on disk. Smaller than `len` for sparse files.
+    pub allocated: u64,
+    /// Inode change time, only used to detect changes without hashing.
+    pub changed: SystemTime,
+    /// Together with `inode` identifies the file on disk so hardlinks can be
+    /// recognized.
+    pub device: u64,
+    pub gid: u32,
+    /// Name of the group, only recorded when owners are mapped by name
+    /// between machines.
+    pub group: Option<String>,
     pub hash: Option<String>,
+    pub inode: u64,
     pub is_dir: bool,
     pub is_symlink: bool,
+    pub last_verified: Option<SystemTime>,
     pub len: u64,
+    /// Number of hardlinks to the inode when the entry was added.
+    pub links: u64,
     pub modified: SystemTime,
+    /// Name of the owner, only recorded when owners are mapped by name
+    /// between machines.
+    pub owner: Option<String>,
     pub permissions: u32,
-    // TODO: Switch back to raw type and dont convert to string when we can serialize directly
-    pub uuid: String,
+    /// Whether the content is supposed to be in the local working tree. In a
+    /// partial checkout this is false for content that was never fetched so a
+    /// missing file is not mistaken for a deleted one.
+    pub present: bool,
+    /// Raw bytes of the target of a symlink so targets that are not valid
+    /// UTF-8 survive the round trip through bincode.
+    pub symlink_target: Option<Vec<u8>>,
+    pub uid: u32,
+    /// Identity of the file. Kept when the file is modified or moved so
+    /// those do not look like a delete and a create.
+    pub uuid: Uuid,
+    /// Extended attributes and ACLs, only recorded when enabled in the
+    /// repository settings.
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+/// How a `RepoFile` is stored in the index and the journal. Same as the
+/// `RepoFile` except that the uuid is kept as its 16 raw bytes, the JSON
+/// output keeps the readable string.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredFile {
+    accessed: SystemTime,
+    allocated: u64,
+    changed: SystemTime,
+    device: u64,
+    gid: u32,
+    group: Option<String>,
+    hash: Option<String>,
+    inode: u64,
+    is_dir: bool,
+    is_symlink: bool,
+    last_verified: Option<SystemTime>,
+    len: u64,
+    links: u64,
+    modified: SystemTime,
+    owner: Option<String>,
+    permissions: u32,
+    present: bool,
+    symlink_target: Option<Vec<u8>>,
+    uid: u32,
+    #[serde(with = "uuidbytes")]
+    uuid: Uuid,
+    xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl<'a> From<&'a RepoFile> for StoredFile {
+    fn from(file: &RepoFile) -> StoredFile {
+        StoredFile {
+            accessed: file.accessed,
+            allocated: file.allocated,
+            changed: file.changed,
+            device: file.device,
+            gid: file.gid,
+            group: file.group.clone(),
+            hash: file.hash.clone(),
+            inode: file.inode,
+            is_dir: file.is_dir,
+            is_symlink: file.is_symlink,
+            last_verified: file.last_verified,
+            len: file.len,
+            links: file.links,
+            modified: file.modified,
+            owner: file.owner.clone(),
+            permissions: file.permissions,
+            present: file.present,
+            symlink_target: file.symlink_target.clone(),
+            uid: file.uid,
+            uuid: file.uuid,
+            xattrs: file.xattrs.clone(),
+        }
+    }
+}
+
+impl From<StoredFile> for RepoFile {
+    fn from(file: StoredFile) -> RepoFile {
+        RepoFile {
+            accessed: file.accessed,
+            allocated: file.allocated,
+            changed: file.changed,
+            device: file.device,
+            gid: file.gid,
+            group: file.group,
+            hash: file.hash,
+            inode: file.inode,
+            is_dir: file.is_dir,
+            is_symlink: file.is_symlink,
+            last_verified: file.last_verified,
+            len: file.len,
+            links: file.links,
+            modified: file.modified,
+            owner: file.owner,
+            permissions: file.permissions,
+            present: file.present,
+            symlink_target: file.symlink_target,
+            uid: file.uid,
+            uuid: file.uuid,
+            xattrs: file.xattrs,
+        }
+    }
 }
 
 impl RepoFile {
     pub fn from_path<P: AsRef<Path> + Debug>(path: P) -> Result<RepoFile, Error> {
-        trace!("repofile::from_path: path- {:?}", path);
+        RepoFile::from_path_with_hash(path, None)
+    }
 
-        let mut file = File::open(&path).context(format_err!("can not open path {:?}", path))?;
-        trace!("repofile::from_path: file - {:?}", file);
+    /// Like `from_path` but uses `known_hash` as hash of a regular file
+    /// instead of reading it. Used for hardlinks to an inode that was already
+    /// hashed.
+    pub fn from_path_with_hash<P: AsRef<Path> + Debug>(path: P, known_hash: Option<String>) -> Result<RepoFile, Error> {
+        trace!("repofile::from_path: path- {:?}", path);
 
         // NOTE: We dont want to follow symlinks as we want to replicate the symlinks
         // in other repositories.
@@ -42,23 +196,202 @@ impl RepoFile {
         trace!("repofile::from_path: metadata - {:?}", metadata);
 
         let is_dir = metadata.is_dir();
+        let is_symlink = metadata.file_type().is_symlink();
 
-        let hash = if is_dir {
+        // Symlinks are stored by their target, opening them would follow the
+        // link and fail for dangling ones.
+        let symlink_target = if is_symlink {
+            Some(read_symlink_target(&path)?)
+        } else {
             None
+        };
+
+        let hash = if is_dir || is_symlink {
+            None
+        } else if known_hash.is_some() {
+            known_hash
         } else {
-            Some(format!("{:x}", Sha256::digest_reader(&mut file)?))
+            Some(hash_path(&path)?)
         };
 
         Ok(RepoFile {
+            accessed: metadata
+                .accessed()
+                .context(format_err!("can not get access time for file {:?}", path))?,
+            allocated: sparse::allocated_bytes(&metadata),
+            changed: changed_time(&metadata),
+            device: metadata.dev(),
+            gid: metadata.gid(),
+            group: None,
             hash: hash,
+            inode: metadata.ino(),
             is_dir: is_dir,
-            is_symlink: metadata.file_type().is_symlink(),
+            is_symlink: is_symlink,
+            // The hash was just calculated from the content so it counts as
+            // verified.
+            last_verified: Some(SystemTime::now()),
             len: metadata.len(),
+            links: metadata.nlink(),
             modified: metadata
                 .modified()
                 .context(format_err!("can not get modified time for file {:?}", path))?,
+            owner: None,
             permissions: metadata.permissions().mode(),
-            uuid: format!("{}", Uuid::new_v4()),
+            present: true,
+            symlink_target: symlink_target,
+            uid: metadata.uid(),
+            uuid: Uuid::new_v4(),
+            xattrs: BTreeMap::default(),
         })
     }
+
+    /// Returns true for a file on disk whose stat data still matches but
+    /// whose content was found to be corrupted. Nothing wrote to it since it
+    /// was added, so the content differs because the copy is damaged, not
+    /// because it was edited. Only meaningful when the stat data matches.
+    pub fn is_corrupted_copy(&self) -> bool {
+        !self.present && !self.is_dir && !self.is_symlink
+    }
+
+    /// Timestamps that differ by at most `tolerance` count as equal so
+    /// filesystems with a coarse timestamp granularity (like FAT with two
+    /// seconds) do not make files look modified.
+    pub fn compare_metadata(&self, metadata: &Metadata, tolerance: Duration) -> Result<StatMatch, Error> {
+        if self.is_dir || metadata.is_dir() {
+            if self.is_dir == metadata.is_dir() {
+                return Ok(StatMatch::Unchanged);
+            } else {
+                return Ok(StatMatch::Changed);
+            }
+        }
+
+        if self.is_symlink != metadata.file_type().is_symlink() {
+            return Ok(StatMatch::Changed);
+        }
+
+        if self.len != metadata.len() {
+            return Ok(StatMatch::Changed);
+        }
+
+        let modified = metadata.modified().context("can not get modified time")?;
+
+        if times_match(modified, self.modified, tolerance) && metadata.ino() == self.inode
+            && times_match(changed_time(metadata), self.changed, tolerance)
+        {
+            Ok(StatMatch::Unchanged)
+        } else {
+            Ok(StatMatch::Unknown)
+        }
+    }
+
+    /// Records the names of the owner and group and the extended attributes
+    /// selected by `filter`.
+    pub fn capture_attributes<P: AsRef<Path> + Debug>(&mut self, path: P, owner_names: bool, filter: XattrFilter) -> Result<(), Error> {
+        if owner_names {
+            self.owner = attributes::user_name(self.uid);
+            self.group = attributes::group_name(self.gid);
+        }
+
+        self.xattrs = attributes::read_xattrs(&path, filter)?;
+
+        Ok(())
+    }
+
+    /// Whether the entry is one of multiple hardlinks to the same inode.
+    pub fn is_hardlink(&self) -> bool {
+        !self.is_dir && !self.is_symlink && self.links > 1
+    }
+
+    /// Checks if the symlink at `path` still points to the recorded target.
+    pub fn symlink_target_matches<P: AsRef<Path> + Debug>(&self, path: P) -> Result<bool, Error> {
+        let target = read_symlink_target(&path)?;
+
+        Ok(self.symlink_target.as_ref() == Some(&target))
+    }
+
+    /// Recreates the symlink at `path`. Dangling targets and targets that are
+    /// directories are created the same way as every other target.
+    pub fn create_symlink<P: AsRef<Path> + Debug>(&self, path: P) -> Result<(), Error> {
+        let target = self.symlink_target
+            .as_ref()
+            .ok_or_else(|| format_err!("entry for {:?} has no symlink target", path))?;
+
+        symlink(OsStr::from_bytes(target), &path).context(format_err!("can not create symlink {:?}", path))?;
+
+        Ok(())
+    }
+
+    /// Updates the cached stat data after the content was verified to be
+    /// unchanged so the file does not have to be hashed again next time.
+    pub fn refresh_metadata(&mut self, metadata: &Metadata) -> Result<(), Error> {
+        self.changed = changed_time(metadata);
+        self.inode = metadata.ino();
+        self.modified = metadata.modified().context("can not get modified time")?;
+
+        Ok(())
+    }
+}
+
+fn read_symlink_target<P: AsRef<Path> + Debug>(path: P) -> Result<Vec<u8>, Error> {
+    let target = read_link(&path).context(format_err!("can not read target of symlink {:?}", path))?;
+
+    Ok(target.into_os_string().into_vec())
+}
+
+/// Whether the two timestamps differ by at most `tolerance`.
+pub fn times_match(a: SystemTime, b: SystemTime, tolerance: Duration) -> bool {
+    let difference = match a.duration_since(b) {
+        Ok(difference) => difference,
+        Err(err) => err.duration(),
+    };
+
+    difference <= tolerance
+}
+
+fn changed_time(metadata: &Metadata) -> SystemTime {
+    UNIX_EPOCH + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32)
+}
+
+/// Hashes the content of the file. The holes of sparse files are not read.
+pub fn hash_path<P: AsRef<Path> + Debug>(path: P) -> Result<String, Error> {
+    let mut file = File::open(&path).context(format_err!("can not open path {:?}", path))?;
+    let metadata = file.metadata()
+        .context(format_err!("can not get metadata for file {:?}", path))?;
+
+    if sparse::is_sparse(&metadata) {
+        let regions = sparse::data_regions(&file, metadata.len()).context(format_err!("can not get data regions of {:?}", path))?;
+
+        return sparse::hash_regions(&mut file, metadata.len(), &regions);
+    }
+
+    hash_reader(&mut file)
+}
+
+pub fn hash_reader<R: Read>(reader: &mut R) -> Result<String, Error> {
+    Ok(format!("{:x}", Sha256::digest_reader(reader)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn equal_times_match_without_tolerance() {
+        assert!(times_match(at(100, 0), at(100, 0), Duration::from_millis(0)));
+        assert!(!times_match(at(100, 0), at(100, 1), Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn tolerance_applies_in_both_directions() {
+        let tolerance = Duration::from_millis(2000);
+
+        assert!(times_match(at(100, 0), at(102, 0), tolerance));
+        assert!(times_match(at(102, 0), at(100, 0), tolerance));
+        assert!(!times_match(at(100, 0), at(102, 1), tolerance));
+        assert!(!times_match(at(102, 1), at(100, 0), tolerance));
+    }
 }
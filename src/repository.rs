@@ -1,39 +1,124 @@
-use crossbeam_channel::unbounded;
+use addreport::{
+    AddOutcome,
+    AddReport,
+};
+use attributes;
+use attributes::XattrFilter;
+use crossbeam_channel::{
+    unbounded,
+    Sender,
+};
 use failure::{
     Error,
     ResultExt,
 };
-use index::Index;
+use duplicates::{
+    DuplicateGroup,
+    DuplicatesReport,
+};
+use fsck::{
+    FsckCheckpoint,
+    FsckOptions,
+    FsckReport,
+    ScrubOptions,
+    Verification,
+};
+use gc::{
+    GcOptions,
+    GcReport,
+};
+use history::{
+    FileHistory,
+    RestoreVersion,
+};
+use hardlinks::HardlinkHashes;
+use ignorefilter::IgnoreFilter;
+use index::{
+    Index,
+    IndexEntry,
+    IndexUpdate,
+    FORMAT_VERSION,
+};
+use indexwriter::IndexWriter;
+use movecandidates::MoveCandidates;
 use num_cpus;
+use output;
+use output::OutputFormat;
 use pathclassifier;
 use pathclassifier::PathType;
-use repofile::RepoFile;
+use pathspec::Pathspec;
+use progress::{
+    Progress,
+    ProgressListener,
+};
+use repofile;
+use repofile::{
+    RepoFile,
+    StatMatch,
+};
 use repostatus::RepoStatus;
+use scheduler;
+use scheduler::{
+    Bandwidth,
+    RemoteLimits,
+    SchedulerOptions,
+    TransferJob,
+    TransferPriority,
+};
 use serde_json::{
     from_reader,
     to_writer,
 };
-use sha2::{
-    Digest,
-    Sha256,
+use snapshot::{
+    SnapshotList,
+    SnapshotRestoreReport,
+    SnapshotView,
+};
+use sparse;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
 };
 use std::fmt::Debug;
 use std::fs::{
     create_dir_all,
+    hard_link,
+    remove_dir_all,
+    remove_file,
+    rename,
+    set_permissions,
     symlink_metadata,
     File,
+    Metadata,
+    Permissions,
+};
+use std::os::unix::fs::{
+    MetadataExt,
+    PermissionsExt,
+};
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
 };
-use std::path::Path;
-use std::path::PathBuf;
 use std::sync::{
     Arc,
     Barrier,
-    Mutex,
 };
 use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+};
 use time::PreciseTime;
+use transfer;
+use uuid::Uuid;
 use walkdir::WalkDir;
 
+/// How many paths fsck verifies before it writes a new checkpoint.
+const FSCK_CHECKPOINT_INTERVAL: usize = 100;
+
 #[derive(Debug, Fail)]
 enum RepositoryError {
     #[fail(display = "repository is already initialized")] AlreadyInitialized,
@@ -42,23 +127,108 @@ enum RepositoryError {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Settings {
+    /// Gitignore style patterns that apply to the whole repository in
+    /// addition to the `.syncustignore` files.
+    #[serde(default)]
+    ignore: Vec<String>,
+    /// Record owner and group names and map them back to ids on the machine
+    /// the files are restored on instead of using the numeric ids.
+    #[serde(default)]
+    map_owner_names: bool,
+    /// Timestamps that differ by at most this many milliseconds count as
+    /// unchanged. Needed for filesystems with a coarse granularity like FAT
+    /// (2000) or exFAT (10).
+    #[serde(default)]
+    mtime_tolerance_ms: u64,
+    /// Record POSIX ACLs.
+    #[serde(default)]
+    preserve_acls: bool,
+    /// Restore the owner and group of files. Usually needs root so it is off
+    /// by default.
+    #[serde(default)]
+    preserve_ownership: bool,
+    /// Record extended attributes outside of the `system` and `security`
+    /// namespaces, for example `user.*` tags.
+    #[serde(default)]
+    preserve_xattrs: bool,
+    /// Concurrency and bandwidth limits for the transfers from single
+    /// remotes, by remote name. `local` applies to copies from other paths of
+    /// this repository.
+    #[serde(default)]
+    remote_limits: BTreeMap<String, RemoteLimits>,
+    /// Also restore the recorded access time when files are materialized.
+    /// Otherwise only the modified time is restored.
+    #[serde(default)]
+    restore_atime: bool,
+    /// Versions in the history are kept for this many days after they were
+    /// replaced. They are kept forever if this is not set.
+    #[serde(default)]
+    history_retention_days: Option<u64>,
+    /// Keep a copy of all added content in the object store so replaced
+    /// versions and snapshots can be restored after the working tree
+    /// changed. Needs as much space again as the added content.
+    #[serde(default = "default_store_objects")]
+    store_objects: bool,
     sublayers: usize,
+    /// Bytes per second all transfers together may use. Unlimited if this
+    /// is not set.
+    #[serde(default)]
+    transfer_bandwidth_limit: Option<u64>,
+    /// Transfers running at the same time. Defaults to the number of cpus.
+    #[serde(default)]
+    transfer_concurrency: Option<usize>,
+    /// Order in which transfers are started, `smallest_first`,
+    /// `largest_first` or `path`.
+    #[serde(default)]
+    transfer_priority: TransferPriority,
+    /// How often a failed transfer is retried before giving up.
+    #[serde(default = "default_transfer_retries")]
+    transfer_retries: u32,
+    /// Identifies the repository in the journal. Generated when the settings
+    /// are first loaded for repositories created before it existed.
+    #[serde(default = "Uuid::nil")]
+    uuid: Uuid,
+    /// Format of the index, see `index::FORMAT_VERSION`.
     version: usize,
 }
 
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
+            history_retention_days: None,
+            ignore: Vec::new(),
+            map_owner_names: false,
+            mtime_tolerance_ms: 0,
+            preserve_acls: false,
+            preserve_ownership: false,
+            preserve_xattrs: false,
+            remote_limits: BTreeMap::new(),
+            restore_atime: false,
+            store_objects: default_store_objects(),
             sublayers: 4,
-            version: 1,
+            transfer_bandwidth_limit: None,
+            transfer_concurrency: None,
+            transfer_priority: TransferPriority::default(),
+            transfer_retries: default_transfer_retries(),
+            uuid: Uuid::new_v4(),
+            version: FORMAT_VERSION,
         }
     }
 }
 
+fn default_store_objects() -> bool {
+    true
+}
+
+fn default_transfer_retries() -> u32 {
+    3
+}
+
 #[derive(Debug)]
 pub struct Repository {
     path: PathBuf,
     settings: Settings,
+    progress: Option<Arc<dyn ProgressListener>>,
 }
 
 impl Default for Repository {
@@ -66,6 +236,7 @@ impl Default for Repository {
         Repository {
             path: PathBuf::new(),
             settings: Settings::default(),
+            progress: None,
         }
     }
 }
@@ -78,6 +249,15 @@ impl Repository {
         }
     }
 
+    /// Sends the progress of long running operations like `add`, `status`
+    /// and `fsck` to `listener`.
+    pub fn with_progress(self, listener: Option<Arc<dyn ProgressListener>>) -> Repository {
+        Repository {
+            progress: listener,
+            ..self
+        }
+    }
+
     pub fn open<P: AsRef<Path> + Debug>(path: P) -> Result<Repository, Error> {
         let mut repository = Repository::default().with_path(path);
 
@@ -86,6 +266,7 @@ impl Repository {
         }
 
         repository.load_settings().context("can not load settings")?;
+        repository.upgrade_format()?;
 
         Ok(repository)
     }
@@ -98,7 +279,7 @@ impl Repository {
 
         self.write_settings().context("can not write repo data")?;
 
-        let _ = Index::open(self.get_index_path())?;
+        let _ = self.open_index()?;
 
         Ok(())
     }
@@ -118,71 +299,199 @@ impl Repository {
         unimplemented!();
     }
 
-    pub fn add<P: AsRef<Path> + Debug>(&mut self, paths_to_add: Vec<P>) -> Result<(), Error> {
+    /// Adds untracked paths and updates tracked paths that changed since
+    /// they were added. Paths that can not be added are logged and counted
+    /// in the report instead of stopping the others.
+    pub fn add<P: AsRef<Path> + Debug>(&mut self, paths_to_add: Vec<P>) -> Result<AddReport, Error> {
         if !self.is_inialized() {
             Err(RepositoryError::NotInitialized)?
         }
 
-        for path in paths_to_add {
-            trace!("repository::Repository::add: path - {:?}", path);
+        let pathspec = self.pathspec(&paths_to_add)?;
 
-            self.add_folder(path)?;
+        let progress = self.progress("add");
+
+        let mut report = AddReport::default();
+        for base in pathspec.bases() {
+            trace!("repository::Repository::add: base - {:?}", base);
+
+            for result in self.add_folder(self.path.join(base), &pathspec, &progress)? {
+                match result {
+                    Ok(outcome) => report.record(outcome),
+                    Err(err) => {
+                        let causes = err.causes()
+                            .map(|cause| cause.to_string())
+                            .collect::<Vec<_>>();
+
+                        error!("{}", causes.join(": "));
+                        report.failed_count += 1;
+                    }
+                }
+            }
         }
 
+        progress.finish();
         debug!("finished adding files");
 
-        Ok(())
+        Ok(report)
     }
 
-    pub fn status(&self) -> Result<RepoStatus, Error> {
+    pub fn status(&self, pathspec: &Pathspec) -> Result<RepoStatus, Error> {
         if !self.is_inialized() {
             Err(RepositoryError::NotInitialized)?
         }
 
-        let index = Index::open(self.get_index_path())?;
+        let index = self.open_index()?;
 
         let mut status = RepoStatus::default();
 
         let repo_path = self.path.clone();
         let data_path = self.get_data_path();
+        let ignore = self.ignore_filter()?;
 
-        for entry in WalkDir::new(&repo_path) {
-            let file_path = entry.unwrap().path().to_path_buf();
+        let bases = pathspec.bases();
 
-            if file_path == repo_path {
-                continue;
-            }
+        // Files where the stat data is not enough to decide if they changed.
+        // They get hashed in parallel after the walk.
+        let mut ambiguous = Vec::new();
+
+        let progress = self.progress("status");
 
-            if file_path.starts_with(&data_path) {
+        for base in &bases {
+            let base_path = repo_path.join(base);
+            if symlink_metadata(&base_path).is_err() {
                 continue;
             }
 
-            let path = self.strip_path(&file_path);
-            let index_entry = index.get(&path);
+            let walker = WalkDir::new(&base_path)
+                .into_iter()
+                .filter_entry(|entry| !ignore.is_ignored(entry.path(), entry.file_type().is_dir()));
+
+            for entry in walker {
+                let file_path = match entry {
+                    Ok(entry) => entry.path().to_path_buf(),
+                    Err(err) => {
+                        let path = err.path().map(|path| self.strip_path(path)).unwrap_or_default();
+                        status.record_error(path, &Error::from(err).context("can not walk directory").into());
+                        continue;
+                    }
+                };
 
-            if index_entry.is_err() {
-                status.untracked_paths.insert(path);
-            } else {
-                let metadata = symlink_metadata(&file_path).context(format_err!("can not get metadata for file {:?}", path))?;
+                if file_path == repo_path {
+                    continue;
+                }
 
-                let modified = metadata
-                    .modified()
-                    .context(format_err!("can not get modified time for file {:?}", path))?;
+                if file_path.starts_with(&data_path) {
+                    continue;
+                }
 
-                let index_entry = index_entry.unwrap();
-                if modified != index_entry.modified {
-                    let is_dir = metadata.is_dir();
+                let path = self.strip_path(&file_path);
+                if !pathspec.matches(&path) {
+                    continue;
+                }
 
-                    let hash = if is_dir {
-                        None
-                    } else {
-                        let mut file = File::open(&file_path).context(format_err!("can not open path {:?}", path))?;
-                        Some(format!("{:x}", Sha256::digest_reader(&mut file)?))
-                    };
+                let index_entry = index.get(&path);
+
+                let metadata = match symlink_metadata(&file_path) {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        let err = Error::from(err).context(format_err!("can not get metadata for file {:?}", path));
+                        status.record_error(path, &err.into());
+                        continue;
+                    }
+                };
+                progress.advance(1, 0);
+
+                if index_entry.is_err() {
+                    status.untracked_bytes += metadata.len();
+                    status.untracked_paths.insert(path);
+                    continue;
+                }
+
+                let index_entry = index_entry.unwrap();
+                let stat_match = match index_entry.compare_metadata(&metadata, self.mtime_tolerance()) {
+                    Ok(stat_match) => stat_match,
+                    Err(err) => {
+                        let err = err.context(format_err!("can not compare metadata for file {:?}", path));
+                        status.record_error(path, &err.into());
+                        continue;
+                    }
+                };
 
-                    if index_entry.hash != hash {
+                match stat_match {
+                    StatMatch::Unchanged if index_entry.is_corrupted_copy() => {
+                        status.corrupted_bytes += index_entry.len;
+                        status.corrupted_paths.insert(path);
+                    }
+                    StatMatch::Unchanged => {
+                        status.present_count += 1;
+                        status.present_bytes += index_entry.len;
+                        status.present_allocated_bytes += sparse::allocated_bytes(&metadata);
+                    }
+                    StatMatch::Changed => {
+                        status.changed_bytes += metadata.len();
                         status.changed_paths.insert(path);
                     }
+                    // Symlinks have no content to hash, their target decides.
+                    StatMatch::Unknown if index_entry.is_symlink => match index_entry.symlink_target_matches(&file_path) {
+                        Ok(true) => {
+                            status.present_count += 1;
+                            status.present_bytes += index_entry.len;
+                            status.present_allocated_bytes += sparse::allocated_bytes(&metadata);
+                        }
+                        Ok(false) => {
+                            status.changed_bytes += metadata.len();
+                            status.changed_paths.insert(path);
+                        }
+                        Err(err) => status.record_error(path, &err),
+                    },
+                    StatMatch::Unknown => ambiguous.push((path, index_entry, metadata)),
+                }
+            }
+        }
+
+        progress.finish();
+
+        for (path, mut entry, metadata, hash) in self.hash_entries(ambiguous) {
+            let hash = match hash {
+                Ok(hash) => hash,
+                Err(err) => {
+                    status.record_error(path, &err);
+                    continue;
+                }
+            };
+
+            if entry.hash.as_ref() == Some(&hash) {
+                status.present_count += 1;
+                status.present_bytes += entry.len;
+                status.present_allocated_bytes += sparse::allocated_bytes(&metadata);
+
+                entry.refresh_metadata(&metadata)?;
+                index.set_local(&path, &entry)?;
+            } else {
+                status.changed_bytes += metadata.len();
+                status.changed_paths.insert(path);
+            }
+        }
+
+        for base in &bases {
+            for entry in index.iter_prefix(base)? {
+                let (path, entry) = entry?;
+
+                if !pathspec.matches(&path) {
+                    continue;
+                }
+
+                if symlink_metadata(self.path.join(&path)).is_ok() {
+                    continue;
+                }
+
+                if entry.present {
+                    status.deleted_bytes += entry.len;
+                    status.deleted_paths.insert(path);
+                } else {
+                    status.missing_bytes += entry.len;
+                    status.missing_paths.insert(path);
                 }
             }
         }
@@ -192,56 +501,56 @@ impl Repository {
         Ok(status)
     }
 
-    fn add_folder<P: AsRef<Path> + Debug>(&self, folder_path: P) -> Result<(), Error> {
-        let repo_path = self.path.clone();
-        let data_path = self.get_data_path();
+    /// Hashes the content of the given entries using one worker per cpu.
+    fn hash_entries(&self, entries: Vec<(PathBuf, RepoFile, Metadata)>) -> Vec<(PathBuf, RepoFile, Metadata, Result<String, Error>)> {
+        if entries.is_empty() {
+            return Vec::new();
+        }
+
+        let progress = self.progress("status hash");
+        progress.set_total(entries.len() as u64, entries.iter().map(|entry| entry.2.len()).sum());
+
         let (tx, rx) = unbounded();
+        let (result_tx, result_rx) = unbounded();
 
         let worker = num_cpus::get();
-        let index = Index::open(self.get_index_path())?;
-        let index = Arc::new(Mutex::new(index));
         let barrier = Arc::new(Barrier::new(worker + 1));
 
         for worker in 0..worker {
             let rx = rx.clone();
-            let repo_path = repo_path.clone();
-            let index = Arc::clone(&index);
+            let result_tx = result_tx.clone();
+            let repo_path = self.path.clone();
+            let progress = progress.clone();
             let barrier = Arc::clone(&barrier);
 
             thread::spawn(move || {
-                let repo = Repository::open(&repo_path).expect("can not open worker repository");
-
                 loop {
                     let entry = rx.recv();
-                    debug!("worker {} received message", worker);
+                    debug!("hash worker {} received message", worker);
 
                     if entry.is_err() {
-                        debug!("worker {} has ended", worker);
+                        debug!("hash worker {} has ended", worker);
                         break;
                     }
 
-                    if let Err(err) = repo.add_file(&index, entry.unwrap()) {
-                        error!("{:?}", err)
-                    }
+                    let (path, file, metadata): (PathBuf, RepoFile, Metadata) = entry.unwrap();
+                    let hash = repofile::hash_path(repo_path.join(&path))
+                        .context(format_err!("can not hash file {:?}", path))
+                        .map_err(Error::from);
+                    progress.advance(1, metadata.len());
+
+                    result_tx
+                        .send((path, file, metadata, hash))
+                        .expect("can not send hash result");
                 }
 
-                debug!("worker thread {} is waiting", worker);
+                debug!("hash worker thread {} is waiting", worker);
                 barrier.wait();
             });
         }
 
-        for entry in WalkDir::new(folder_path) {
-            let path = entry.unwrap().path().to_path_buf();
-
-            if path == repo_path {
-                continue;
-            }
-
-            if path.starts_with(&data_path) {
-                continue;
-            }
-
-            tx.send(path).expect("can not send path");
+        for entry in entries {
+            tx.send(entry).expect("can not send entry");
         }
 
         debug!("dropping tx channel");
@@ -249,130 +558,2170 @@ impl Repository {
 
         debug!("main thread is waiting");
         barrier.wait();
+        progress.finish();
 
-        Ok(())
+        let mut out = Vec::new();
+        while let Ok(result) = result_rx.try_recv() {
+            out.push(result);
+        }
+
+        out
     }
 
-    fn strip_path<P: AsRef<Path> + Debug>(&self, path: P) -> PathBuf {
-        if path.as_ref().starts_with(&self.path) {
-            path.as_ref()
-                .strip_prefix(&self.path)
-                .expect("can not strip repo path")
-                .to_path_buf()
-        } else {
-            path.as_ref().to_path_buf()
+    /// Stops tracking the given paths and everything below them. Unless
+    /// `cached` is set the paths are also removed from disk.
+    pub fn remove<P: AsRef<Path> + Debug>(&self, paths_to_remove: Vec<P>, cached: bool) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+
+        for path in paths_to_remove {
+            trace!("repository::Repository::remove: path - {:?}", path);
+
+            let path = self.relative_path(&path)?;
+            if path.as_os_str().is_empty() {
+                bail!("refusing to remove the repository root")
+            }
+
+            let file_path = self.path.join(&path);
+            let entries = index.entries_with_prefix(&path)?;
+
+            if entries.is_empty() {
+                bail!("path {:?} is not tracked by the repo", path)
+            }
+
+            if !cached {
+                if let Ok(metadata) = symlink_metadata(&file_path) {
+                    if metadata.is_dir() {
+                        remove_dir_all(&file_path).context(format_err!("can not remove directory {:?}", file_path))?;
+                    } else {
+                        remove_file(&file_path).context(format_err!("can not remove file {:?}", file_path))?;
+                    }
+                }
+            }
+
+            for entry_path in entries.keys() {
+                debug!("removing {:?} from index", entry_path);
+                index.delete(entry_path)?;
+            }
         }
+
+        Ok(())
     }
 
-    fn add_file<P: AsRef<Path> + Debug>(&self, index: &Arc<Mutex<Index>>, file_path: P) -> Result<(), Error> {
-        if file_path.as_ref().starts_with(self.get_data_path()) {
-            bail!("can not add file that is inside the data dir")
+    /// Moves a tracked path and everything below it to a new location on disk
+    /// and in the index. If the source is not on disk anymore only the index
+    /// is updated.
+    pub fn rename<P: AsRef<Path> + Debug, Q: AsRef<Path> + Debug>(&self, source_path: P, destination_path: Q) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
         }
 
-        debug!("add_file: adding file {:?}", file_path);
+        let index = self.open_index()?;
 
-        let start = PreciseTime::now();
+        let source = self.relative_path(&source_path)?;
+        if source.as_os_str().is_empty() {
+            bail!("refusing to move the repository root")
+        }
 
-        let path = self.strip_path(&file_path);
+        let entries = index.entries_with_prefix(&source)?;
 
-        trace!("add_file: path - {:?}", path);
+        if entries.is_empty() {
+            bail!("path {:?} is not tracked by the repo", source)
+        }
 
-        debug!("add_file: checking if path is already tracked");
-        if index.lock().unwrap().contains(&path) {
-            warn!("file {:?} is already tracked by the repo", file_path);
-            return Ok(());
+        let mut destination = self.relative_path(&destination_path)?;
+        if self.path.join(&destination).is_dir() {
+            let file_name = source
+                .file_name()
+                .ok_or_else(|| format_err!("can not get file name of path {:?}", source))?;
+
+            destination = destination.join(file_name);
         }
 
-        debug!("add_file: creating repo_file from file_path");
-        let file = RepoFile::from_path(&file_path).context(format_err!(
-            "can not
-        create file from path {:?}",
-            file_path
-        ))?;
+        if destination.starts_with(&source) {
+            bail!("can not move {:?} into itself", source)
+        }
 
-        let checking = PreciseTime::now();
+        if index.contains(&destination) {
+            bail!("destination {:?} is already tracked by the repo", destination)
+        }
 
-        index.lock().unwrap().set(path, &file)?;
-        let index = PreciseTime::now();
+        let source_path = self.path.join(&source);
+        let destination_path = self.path.join(&destination);
 
-        debug!(
-            "added file {:?}: checking - {:?}, index - {:?}",
-            file_path,
-            start.to(checking),
-            checking.to(index),
-        );
+        if symlink_metadata(&source_path).is_ok() {
+            rename(&source_path, &destination_path).context(format_err!(
+                "can not move {:?} to {:?}",
+                source_path,
+                destination_path
+            ))?;
+        }
+
+        for (path, entry) in entries {
+            let relative = path.strip_prefix(&source)
+                .expect("index entry does not start with source")
+                .to_path_buf();
+
+            let new_path = if relative.as_os_str().is_empty() {
+                destination.clone()
+            } else {
+                destination.join(relative)
+            };
+
+            debug!("moving {:?} to {:?} in index", path, new_path);
+            index.set(&new_path, &entry)?;
+            index.delete(&path)?;
+        }
 
         Ok(())
     }
 
-    fn clone_local<P: AsRef<Path> + Debug>(&self, source_path: P) -> Result<(), Error> {
+    pub fn history<P: AsRef<Path> + Debug>(&self, path: P) -> Result<FileHistory, Error> {
         if !self.is_inialized() {
             Err(RepositoryError::NotInitialized)?
         }
 
-        let src_repo = Repository::open(source_path).context("can not open source repository")?;
+        let index = self.open_index()?;
+        let path = self.relative_path(&path)?;
 
-        if !src_repo.is_inialized() {
-            bail!("source repository is not initialized")
+        let versions = index.history(&path)?;
+        if versions.is_empty() {
+            bail!("path {:?} has no recorded versions", path)
+        }
+
+        Ok(FileHistory {
+            path: path,
+            versions: versions,
+        })
+    }
+
+    /// Brings back an earlier version of a file or symlink. The content is
+    /// copied from the object store or another path in the repository that
+    /// has the same content. Fetching it from remotes is not supported yet.
+    pub fn restore<P: AsRef<Path> + Debug>(&self, path: P, version: &RestoreVersion) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
         }
 
-        let src_index = Index::open(src_repo.get_index_path()).context("can not open source repository index")?;
+        let index = self.open_index()?;
+        let path = self.relative_path(&path)?;
+        let file_path = self.path.join(&path);
+
+        let versions = index.history(&path)?;
+        let version = version
+            .select(&versions)
+            .ok_or_else(|| format_err!("can not find version {:?} of path {:?}", version, path))?;
 
-        let index = Index::open(self.get_index_path()).context("can not open repository index")?;
+        let recorded = index
+            .recorded_file(version.sequence)?
+            .ok_or_else(|| format_err!("version {} of path {:?} is not recorded in the journal", version.sequence, path))?;
 
-        for (path, metadata) in src_index.entries()? {
-            index.set(path, &metadata)?;
+        if recorded.is_dir {
+            bail!("version {} of path {:?} is a directory", version.sequence, path)
         }
 
-        Ok(())
-    }
+        // Overwriting content that was never added would lose it for good.
+        if let Ok(current) = index.get(&path) {
+            match self.verify_entry(&path, &current, false)? {
+                Verification::Intact
+                    if current.hash == recorded.hash && current.symlink_target == recorded.symlink_target
+                        && current.permissions == recorded.permissions =>
+                {
+                    info!("{:?} already matches version {}", path, version.sequence);
+                    return Ok(());
+                }
+                Verification::Modified | Verification::Corrupted => {
+                    bail!("{:?} has changes that are not added, add them before restoring", path)
+                }
+                _ => {}
+            }
+        } else if symlink_metadata(&file_path).is_ok() {
+            bail!("{:?} exists but is not tracked, add it before restoring", path)
+        }
 
-    fn write_settings(&self) -> Result<(), Error> {
-        let settings_path = self.get_settings_path();
-        let settings_file = File::create(&settings_path).context(format_err!(
-            "can not create settings file {:?}",
-            settings_path
-        ))?;
+        if let Some(parent) = file_path.parent() {
+            create_dir_all(parent).context(format_err!("can not create parent directory of {:?}", path))?;
+        }
 
-        to_writer(&settings_file, &self.settings).context("can not serialize repository settings to settings file")?;
+        if recorded.is_symlink {
+            info!("restoring version {} of symlink {:?}", version.sequence, path);
+
+            if symlink_metadata(&file_path).is_ok() {
+                remove_file(&file_path).context(format_err!("can not remove {:?} to restore the symlink", path))?;
+            }
+
+            recorded.create_symlink(&file_path)?;
+            self.apply_attributes(&recorded, &file_path)?;
+        } else {
+            let hash = recorded
+                .hash
+                .as_ref()
+                .ok_or_else(|| format_err!("version {} of path {:?} has no content", version.sequence, path))?;
+
+            let source = self.find_intact_copy(&index, hash)?
+                .ok_or_else(|| format_err!("no copy of version {} of {:?} is present locally", version.sequence, path))?;
+
+            info!("restoring version {} of {:?} from {:?}", version.sequence, path, source);
+
+            transfer::transfer(&source, &file_path, hash, self.get_tmp_path(), &self.bandwidth())?;
+            self.apply_attributes(&recorded, &file_path)?;
+            set_permissions(&file_path, Permissions::from_mode(recorded.permissions))
+                .context(format_err!("can not set permissions for file {:?}", path))?;
+        }
+
+        let mut entry = RepoFile::from_path(&file_path)?;
+        if entry.hash != recorded.hash || entry.symlink_target != recorded.symlink_target {
+            bail!("restored {:?} does not match version {}", path, version.sequence)
+        }
+
+        entry.capture_attributes(&file_path, self.settings.map_owner_names, self.xattr_filter())?;
+
+        // Hashing the restored file reads it, so the times are only set
+        // afterwards to keep the restored access time.
+        self.apply_times(&recorded, &file_path)?;
+        let metadata = symlink_metadata(&file_path).context(format_err!("can not get metadata for file {:?}", path))?;
+        entry.refresh_metadata(&metadata)?;
+        entry.accessed = recorded.accessed;
+
+        entry.uuid = version.uuid;
+
+        index.set(&path, &entry)?;
 
         Ok(())
     }
 
-    fn load_settings(&mut self) -> Result<(), Error> {
-        let settings_file = File::open(self.get_settings_path()).context("can not open settings file")?;
-        let settings: Settings = from_reader(settings_file).context("can not deserialize settings")?;
+    pub fn snapshot_create(&self, name: &str) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
 
-        self.settings = settings;
+        if name.is_empty() {
+            bail!("snapshot name can not be empty")
+        }
+
+        let index = self.open_index()?;
+        let snapshot = index.create_snapshot(name)?;
+
+        // Content added while the object store was off is copied into it now
+        // so the snapshot can be restored after the working tree changed.
+        if self.settings.store_objects {
+            for (path, entry) in index.entries_at(snapshot.sequence)? {
+                let hash = match entry.hash {
+                    Some(ref hash) => hash,
+                    None => continue,
+                };
+
+                if self.get_object_path(hash).exists() {
+                    continue;
+                }
+
+                match self.find_intact_copy(&index, hash)? {
+                    Some(source) => self.store_object(&source, hash)?,
+                    None => warn!("content of {:?} in snapshot {} is not present locally", path, snapshot.name),
+                }
+            }
+        }
+
+        info!("created snapshot {} at sequence {}", snapshot.name, snapshot.sequence);
 
         Ok(())
     }
 
-    fn get_data_path(&self) -> PathBuf {
-        self.path.clone().join(".syncust")
-    }
+    pub fn snapshot_list(&self) -> Result<SnapshotList, Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
 
-    fn get_index_path(&self) -> PathBuf {
-        self.get_data_path().join("index.rocksdb")
+        let index = self.open_index()?;
+
+        Ok(SnapshotList {
+            snapshots: index.snapshots()?,
+        })
     }
 
-    fn get_settings_path(&self) -> PathBuf {
-        self.get_data_path().join("settings.json")
+    pub fn snapshot_show(&self, name: &str) -> Result<SnapshotView, Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+        let snapshot = index
+            .snapshot(name)?
+            .ok_or_else(|| format_err!("snapshot {} does not exist", name))?;
+
+        let entries = index
+            .entries_at(snapshot.sequence)?
+            .into_iter()
+            .map(|(path, file)| IndexEntry {
+                path: path,
+                file: file,
+            })
+            .collect();
+
+        Ok(SnapshotView {
+            snapshot: snapshot,
+            entries: entries,
+        })
     }
 
-    fn is_inialized(&self) -> bool {
-        self.get_data_path().exists()
+    pub fn snapshot_delete(&self, name: &str) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+        index.delete_snapshot(name)?;
+
+        Ok(())
     }
 
-    pub fn debug_tracked_files(&self) -> Result<(), Error> {
+    /// Recreates the tree of a snapshot in a new directory. The content is
+    /// copied from the object store or intact paths in the repository with
+    /// the same hash by the transfer scheduler. Fetching it from remotes is not supported yet so
+    /// files without a local copy are reported as missing.
+    pub fn snapshot_restore<P: AsRef<Path> + Debug>(
+        &self,
+        name: &str,
+        destination_path: P,
+        options: &SchedulerOptions,
+    ) -> Result<SnapshotRestoreReport, Error> {
         if !self.is_inialized() {
             Err(RepositoryError::NotInitialized)?
         }
 
-        let index = Index::open(self.get_index_path())?;
+        let destination_path = destination_path.as_ref();
+        if destination_path.exists() {
+            bail!("destination {:?} does already exist, refusing to continue", destination_path)
+        }
 
-        index.debug_tracked_files()?;
+        let index = self.open_index()?;
+        let snapshot = index
+            .snapshot(name)?
+            .ok_or_else(|| format_err!("snapshot {} does not exist", name))?;
 
-        Ok(())
+        create_dir_all(destination_path).context(format_err!("can not create destination dir {:?}", destination_path))?;
+
+        let mut report = SnapshotRestoreReport::default();
+        let mut directories = Vec::new();
+
+        // First restored path of every hardlinked inode so the other links
+        // can be recreated as hardlinks instead of copies once its content
+        // is there.
+        let mut hardlinks = BTreeMap::new();
+        let mut links = Vec::new();
+
+        let mut jobs = Vec::new();
+        let mut pending = BTreeMap::new();
+
+        for (path, entry) in index.entries_at(snapshot.sequence)? {
+            let file_path = destination_path.join(&path);
+
+            if entry.is_dir {
+                create_dir_all(&file_path).context(format_err!("can not create directory {:?}", file_path))?;
+                self.apply_attributes(&entry, &file_path)?;
+                directories.push((file_path, entry));
+                continue;
+            }
+
+            if entry.is_symlink {
+                if let Some(parent) = file_path.parent() {
+                    create_dir_all(parent).context(format_err!("can not create parent directory of {:?}", file_path))?;
+                }
+
+                entry.create_symlink(&file_path)?;
+                self.apply_attributes(&entry, &file_path)?;
+                self.apply_times(&entry, &file_path)?;
+                report.restored_count += 1;
+                continue;
+            }
+
+            if entry.is_hardlink() {
+                if let Some(link_source) = hardlinks.get(&(entry.device, entry.inode, entry.hash.clone())) {
+                    links.push((path, file_path, PathBuf::clone(link_source)));
+                    continue;
+                }
+            }
+
+            let hash = match entry.hash {
+                Some(ref hash) => hash.clone(),
+                None => {
+                    report.missing_paths.insert(path);
+                    continue;
+                }
+            };
+
+            let source = match self.find_intact_copy(&index, &hash)? {
+                Some(source) => source,
+                None => {
+                    report.missing_paths.insert(path);
+                    continue;
+                }
+            };
+
+            if let Some(parent) = file_path.parent() {
+                create_dir_all(parent).context(format_err!("can not create parent directory of {:?}", file_path))?;
+            }
+
+            if entry.is_hardlink() {
+                hardlinks.insert((entry.device, entry.inode, entry.hash.clone()), file_path.clone());
+            }
+
+            jobs.push(TransferJob {
+                remote: scheduler::LOCAL_REMOTE.to_string(),
+                source: source,
+                destination: file_path.clone(),
+                hash: hash,
+                len: entry.len,
+            });
+            pending.insert(file_path, (path, entry));
+        }
+
+        let mut restored = BTreeSet::new();
+        for (job, result) in scheduler::run(jobs, options, self.get_tmp_path()) {
+            let (path, entry) = pending
+                .remove(&job.destination)
+                .expect("transfer result for a path that was not scheduled");
+
+            if let Err(err) = result {
+                let causes = err.causes()
+                    .map(|cause| cause.to_string())
+                    .collect::<Vec<_>>();
+
+                error!("{}", causes.join(": "));
+                report.failed_paths.insert(path);
+                continue;
+            }
+
+            self.apply_attributes(&entry, &job.destination)?;
+            set_permissions(&job.destination, Permissions::from_mode(entry.permissions))
+                .context(format_err!("can not set permissions for file {:?}", job.destination))?;
+            self.apply_times(&entry, &job.destination)?;
+
+            report.restored_count += 1;
+            report.restored_bytes += entry.len;
+            restored.insert(job.destination);
+        }
+
+        for (path, file_path, link_source) in links {
+            if !restored.contains(&link_source) {
+                report.failed_paths.insert(path);
+                continue;
+            }
+
+            if let Some(parent) = file_path.parent() {
+                create_dir_all(parent).context(format_err!("can not create parent directory of {:?}", file_path))?;
+            }
+
+            hard_link(&link_source, &file_path).context(format_err!("can not link {:?} to {:?}", link_source, file_path))?;
+            report.restored_count += 1;
+        }
+
+        // Directory permissions and times are set last, deepest first, so
+        // read only directories do not prevent restoring their content and
+        // creating the content does not change the times again.
+        for (directory, entry) in directories.into_iter().rev() {
+            set_permissions(&directory, Permissions::from_mode(entry.permissions))
+                .context(format_err!("can not set permissions for directory {:?}", directory))?;
+            self.apply_times(&entry, &directory)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Removes objects from the object store that are not referenced by the
+    /// index, the retained history or any snapshot. Remotes are not cleaned
+    /// up as there are none yet.
+    pub fn gc(&self, options: &GcOptions) -> Result<GcReport, Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+        let now = SystemTime::now();
+
+        let history_since = match self.settings.history_retention_days {
+            Some(days) => Some(
+                days.checked_mul(24 * 60 * 60)
+                    .and_then(|seconds| now.checked_sub(Duration::from_secs(seconds)))
+                    .ok_or_else(|| format_err!("history_retention_days {} is too large", days))?,
+            ),
+            None => None,
+        };
+
+        let referenced = index.referenced_hashes(history_since)?;
+
+        let mut report = GcReport::default();
+        report.referenced_hashes = referenced.len();
+        report.dry_run = options.dry_run;
+
+        let objects_path = self.get_objects_path();
+        if !objects_path.is_dir() {
+            debug!("object store {:?} does not exist, nothing to collect", objects_path);
+            return Ok(report);
+        }
+
+        for entry in WalkDir::new(&objects_path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry.context("can not walk object store")?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            report.scanned_count += 1;
+
+            let hash = entry.file_name().to_string_lossy().into_owned();
+            if referenced.contains(&hash) {
+                continue;
+            }
+
+            let metadata = entry
+                .metadata()
+                .context(format_err!("can not get metadata for object {}", hash))?;
+
+            let modified = metadata
+                .modified()
+                .context(format_err!("can not get modified time for object {}", hash))?;
+
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age < options.grace_period {
+                report.recent_count += 1;
+                continue;
+            }
+
+            if !options.dry_run {
+                debug!("removing unreferenced object {}", hash);
+                remove_file(entry.path()).context(format_err!("can not remove object {}", hash))?;
+            }
+
+            report.unreferenced_bytes += metadata.len();
+            report.unreferenced_hashes.push(hash);
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the absolute path of a copy of the content with the given
+    /// hash. Prefers the object store and falls back to a tracked path that
+    /// is verified to be intact.
+    fn find_intact_copy(&self, index: &Index, hash: &str) -> Result<Option<PathBuf>, Error> {
+        let object_path = self.get_object_path(hash);
+        if object_path.is_file() {
+            match repofile::hash_path(&object_path) {
+                Ok(ref object_hash) if object_hash == hash => return Ok(Some(object_path)),
+                Ok(object_hash) => warn!("object {} is corrupted, its content has hash {}", hash, object_hash),
+                Err(err) => warn!("can not read object {}: {}", hash, err),
+            }
+        }
+
+        for path in index.paths_for_hash(hash)? {
+            let entry = index.get(&path)?;
+            if entry.present && self.verify_entry(&path, &entry, false)? == Verification::Intact {
+                return Ok(Some(self.path.join(path)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Copies the content of a regular file into the object store unless it
+    /// is already there. Objects are read only so they are not changed by
+    /// accident.
+    fn store_object<P: AsRef<Path> + Debug>(&self, file_path: P, hash: &str) -> Result<(), Error> {
+        if !self.settings.store_objects {
+            return Ok(());
+        }
+
+        let object_path = self.get_object_path(hash);
+        if object_path.exists() {
+            return Ok(());
+        }
+
+        if let Some(parent) = object_path.parent() {
+            create_dir_all(parent).context(format_err!("can not create object directory {:?}", parent))?;
+        }
+
+        debug!("storing content of {:?} as object {}", file_path, hash);
+        transfer::transfer(&file_path, &object_path, hash, self.get_tmp_path(), &Bandwidth::default())
+            .context(format_err!("can not store content of {:?} in the object store", file_path))?;
+        set_permissions(&object_path, Permissions::from_mode(0o444))
+            .context(format_err!("can not set permissions for object {}", hash))?;
+
+        Ok(())
+    }
+
+    pub fn fsck(&self, options: &FsckOptions) -> Result<FsckReport, Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+        let mut report = FsckReport::default();
+
+        let (entries, undecodable) = index.checked_entries();
+        report.undecodable_entries = undecodable.len();
+
+        if options.repair {
+            for key in undecodable {
+                warn!("removing undecodable index entry {:?}", key);
+                index.delete_raw(&key)?;
+            }
+        }
+
+        // Every parent of a tracked path has to be tracked as a directory as
+        // well otherwise we can not recreate the tree on other repositories.
+        for path in entries.keys() {
+            let mut parent = path.parent();
+
+            while let Some(directory) = parent {
+                if directory.as_os_str().is_empty() {
+                    break;
+                }
+
+                match entries.get(directory) {
+                    Some(entry) if entry.is_dir => {}
+                    _ => {
+                        report.missing_directories.insert(directory.to_path_buf());
+                    }
+                }
+
+                parent = directory.parent();
+            }
+        }
+
+        if options.repair {
+            for directory in &report.missing_directories {
+                let directory_path = self.path.join(directory);
+                if !directory_path.is_dir() {
+                    continue;
+                }
+
+                let file = RepoFile::from_path(&directory_path).context(format_err!("can not create file from path {:?}", directory_path))?;
+                index.set(directory, &file)?;
+                report.repaired_paths.insert(directory.clone());
+            }
+        }
+
+        let checkpoint = if options.resume {
+            self.load_fsck_checkpoint()
+                .context("can not load fsck checkpoint")?
+        } else {
+            None
+        };
+
+        // Only count what is going to be checked so the ETA of a resumed run
+        // is not thrown off by the entries it skips.
+        let progress = self.progress("fsck");
+        let pending = entries.iter().filter(|&(path, entry)| {
+            entry.present && checkpoint
+                .as_ref()
+                .map_or(true, |checkpoint| path > &checkpoint.last_path)
+        });
+        progress.set_total(pending.clone().count() as u64, pending.map(|(_, entry)| entry.len).sum());
+
+        for (path, entry) in &entries {
+            if let Some(ref checkpoint) = checkpoint {
+                if path <= &checkpoint.last_path {
+                    report.skipped_count += 1;
+                    continue;
+                }
+            }
+
+            trace!("repository::Repository::fsck: path - {:?}", path);
+
+            // Content that was never fetched has nothing to verify. Copies
+            // that were found to be corrupted are still checked so they can
+            // be repaired.
+            if !entry.present && symlink_metadata(self.path.join(path)).is_err() {
+                report.skipped_count += 1;
+                continue;
+            }
+
+            let verification = match self.verify_entry(path, entry, options.fast) {
+                Ok(verification) => verification,
+                Err(err) => {
+                    report.record_error(path.clone(), &err);
+                    continue;
+                }
+            };
+
+            match verification {
+                Verification::Missing => {
+                    report.missing_paths.insert(path.clone());
+
+                    if options.repair {
+                        if entry.is_dir {
+                            let file_path = self.path.join(path);
+
+                            create_dir_all(&file_path).context(format_err!("can not create directory {:?}", path))?;
+                            self.apply_attributes(entry, &file_path)?;
+                            set_permissions(&file_path, Permissions::from_mode(entry.permissions))
+                                .context(format_err!("can not set permissions for directory {:?}", path))?;
+                            self.apply_times(entry, &file_path)?;
+                        } else if entry.is_symlink {
+                            let file_path = self.path.join(path);
+
+                            entry.create_symlink(&file_path)?;
+                            self.apply_attributes(entry, &file_path)?;
+                            self.apply_times(entry, &file_path)?;
+                        } else {
+                            // The content is gone so stop claiming that we
+                            // have it locally.
+                            let mut entry = entry.clone();
+                            entry.present = false;
+                            index.set_local(path, &entry)?;
+                        }
+
+                        report.repaired_paths.insert(path.clone());
+                    }
+
+                    continue;
+                }
+                Verification::Modified => {
+                    report.modified_paths.insert(path.clone());
+                }
+                Verification::Corrupted => {
+                    report.corrupted_paths.insert(path.clone());
+
+                    if options.repair_from_duplicates && !options.fast && self.repair_from_duplicate(&index, path, entry)? {
+                        report.repaired_paths.insert(path.clone());
+                    } else if options.repair && entry.present {
+                        let mut entry = entry.clone();
+                        entry.present = false;
+                        entry.last_verified = None;
+                        index.set_local(path, &entry)?;
+                    }
+                }
+                Verification::Intact => {
+                    if !options.fast && !entry.is_dir {
+                        let mut entry = entry.clone();
+                        entry.present = true;
+                        entry.last_verified = Some(SystemTime::now());
+                        index.set_local(path, &entry)?;
+                    }
+                }
+            }
+
+            report.checked_count += 1;
+            report.checked_bytes += entry.len;
+            progress.advance(1, entry.len);
+
+            if report.checked_count % FSCK_CHECKPOINT_INTERVAL == 0 {
+                self.write_fsck_checkpoint(path)
+                    .context("can not write fsck checkpoint")?;
+            }
+        }
+
+        progress.finish();
+
+        self.fsck_objects(&index, options, &mut report)?;
+
+        self.remove_fsck_checkpoint()
+            .context("can not remove fsck checkpoint")?;
+
+        Ok(report)
+    }
+
+    /// Hashes every object in the object store that is still referenced
+    /// and looks for content in the working tree that has no object. With
+    /// `repair` corrupted objects are removed and both are stored again from
+    /// an intact copy in the working tree when there is one.
+    fn fsck_objects(&self, index: &Index, options: &FsckOptions, report: &mut FsckReport) -> Result<(), Error> {
+        let referenced = index.referenced_hashes(None)?;
+
+        let progress = self.progress("fsck objects");
+        progress.set_total(referenced.len() as u64, 0);
+
+        for hash in &referenced {
+            let object_path = self.get_object_path(hash);
+
+            if object_path.is_file() {
+                if !options.fast {
+                    match repofile::hash_path(&object_path) {
+                        Ok(ref object_hash) if object_hash == hash => {}
+                        Ok(object_hash) => {
+                            warn!("object {} is corrupted, its content has hash {}", hash, object_hash);
+                            report.corrupted_objects.insert(hash.clone());
+                        }
+                        Err(err) => report.record_error(self.strip_path(&object_path), &err),
+                    }
+                }
+            } else if self.settings.store_objects && self.is_present(index, hash)? {
+                report.missing_objects.insert(hash.clone());
+            }
+
+            progress.advance(1, 0);
+        }
+
+        progress.finish();
+
+        if !options.repair {
+            return Ok(());
+        }
+
+        let damaged: Vec<String> = report
+            .corrupted_objects
+            .iter()
+            .chain(&report.missing_objects)
+            .cloned()
+            .collect();
+
+        for hash in damaged {
+            let object_path = self.get_object_path(&hash);
+            if object_path.exists() {
+                warn!("removing corrupted object {}", hash);
+                remove_file(&object_path).context(format_err!("can not remove corrupted object {}", hash))?;
+            }
+
+            match self.find_intact_copy(index, &hash)? {
+                Some(source) => self.store_object(&source, &hash)?,
+                None => warn!("no intact copy of object {} is present locally", hash),
+            }
+
+            if object_path.is_file() {
+                report.repaired_objects.insert(hash);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if some path with the given content is present in the
+    /// working tree.
+    fn is_present(&self, index: &Index, hash: &str) -> Result<bool, Error> {
+        for path in index.paths_for_hash(hash)? {
+            if index.get(&path)?.present {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn duplicates(&self) -> Result<DuplicatesReport, Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+        let mut report = DuplicatesReport::default();
+
+        for (hash, paths) in index.duplicate_hashes()? {
+            let len = match paths.iter().next() {
+                Some(path) => index.get(path)?.len,
+                None => continue,
+            };
+
+            // Hardlinks share their content so only separate inodes take up
+            // additional space.
+            let mut inodes = BTreeSet::new();
+            for path in &paths {
+                let entry = index.get(path)?;
+                inodes.insert((entry.device, entry.inode));
+            }
+
+            report.duplicate_bytes += len * (inodes.len() as u64 - 1);
+            report.groups.push(DuplicateGroup {
+                hash: hash,
+                len: len,
+                paths: paths,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Verifies the content of the entries that have gone the longest without
+    /// being verified first. Stops as soon as one of the limits in `options`
+    /// is reached so a full pass over the repository can be spread over
+    /// multiple runs.
+    pub fn scrub(&self, options: &ScrubOptions) -> Result<FsckReport, Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+        let mut report = FsckReport::default();
+
+        let mut entries = index
+            .entries()?
+            .into_iter()
+            .filter(|&(_, ref entry)| !entry.is_dir && entry.present)
+            .collect::<Vec<_>>();
+
+        // Entries that have never been verified sort first as None is smaller
+        // than any Some.
+        entries.sort_by(|a, b| a.1.last_verified.cmp(&b.1.last_verified));
+
+        let start = Instant::now();
+
+        for (path, mut entry) in entries {
+            if let Some(max_duration) = options.max_duration {
+                if start.elapsed() >= max_duration {
+                    break;
+                }
+            }
+
+            if let Some(max_bytes) = options.max_bytes {
+                if report.checked_bytes >= max_bytes {
+                    break;
+                }
+            }
+
+            trace!("repository::Repository::scrub: path - {:?}", path);
+
+            if symlink_metadata(self.path.join(&path)).is_err() {
+                report.missing_paths.insert(path);
+                continue;
+            }
+
+            // Files that were written to are not hashed, they would only use
+            // up the budget and are not verified until they are added again.
+            match self.stat_matches(&path, &entry) {
+                Ok(true) => {}
+                Ok(false) => {
+                    report.modified_paths.insert(path);
+                    continue;
+                }
+                Err(err) => {
+                    report.record_error(path, &err);
+                    continue;
+                }
+            }
+
+            let verification = match self.verify_entry(&path, &entry, false) {
+                Ok(verification) => verification,
+                Err(err) => {
+                    report.record_error(path, &err);
+                    continue;
+                }
+            };
+
+            match verification {
+                Verification::Missing => {
+                    report.missing_paths.insert(path);
+                    continue;
+                }
+                Verification::Modified => {
+                    report.modified_paths.insert(path);
+                    continue;
+                }
+                Verification::Corrupted => {
+                    // The copy does not have the content anymore. status and
+                    // fsck report it until it is repaired.
+                    entry.present = false;
+                    entry.last_verified = None;
+                    report.corrupted_paths.insert(path.clone());
+                }
+                Verification::Intact => {
+                    entry.last_verified = Some(SystemTime::now());
+                }
+            }
+
+            report.checked_count += 1;
+            report.checked_bytes += entry.len;
+
+            index.set_local(&path, &entry)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Replaces the content of a corrupted file with an intact copy of the
+    /// same content from another path in the repository. Returns false if
+    /// there is no such copy or the file was written to since it was checked,
+    /// edits that were not added yet are never replaced.
+    fn repair_from_duplicate<P: AsRef<Path> + Debug>(&self, index: &Index, path: P, entry: &RepoFile) -> Result<bool, Error> {
+        let hash = match entry.hash {
+            Some(ref hash) => hash,
+            None => return Ok(false),
+        };
+
+        for other_path in index.paths_for_hash(hash)? {
+            if other_path == path.as_ref() {
+                continue;
+            }
+
+            let other = index.get(&other_path)?;
+            if !other.present || self.verify_entry(&other_path, &other, false)? != Verification::Intact {
+                continue;
+            }
+
+            if !self.stat_matches(&path, entry)? {
+                warn!("not repairing {:?}, it was modified since it was added", path);
+                return Ok(false);
+            }
+
+            let file_path = self.path.join(&path);
+            let corrupted_hash = repofile::hash_path(&file_path).context(format_err!("can not hash file {:?}", path))?;
+            warn!(
+                "replacing corrupted content of {:?} with hash {} by the content of {:?} with hash {}",
+                path,
+                corrupted_hash,
+                other_path,
+                hash
+            );
+
+            transfer::transfer(self.path.join(&other_path), &file_path, hash, self.get_tmp_path(), &self.bandwidth())?;
+            self.apply_attributes(entry, &file_path)?;
+            set_permissions(&file_path, Permissions::from_mode(entry.permissions))
+                .context(format_err!("can not set permissions for file {:?}", path))?;
+            self.apply_times(entry, &file_path)?;
+
+            let metadata = symlink_metadata(&file_path).context(format_err!("can not get metadata for file {:?}", path))?;
+
+            let mut entry = entry.clone();
+            entry.refresh_metadata(&metadata)?;
+            entry.present = true;
+            entry.last_verified = Some(SystemTime::now());
+            index.set_local(&path, &entry)?;
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn verify_entry<P: AsRef<Path> + Debug>(&self, path: P, entry: &RepoFile, fast: bool) -> Result<Verification, Error> {
+        let file_path = self.path.join(&path);
+        let metadata = match symlink_metadata(&file_path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(Verification::Missing),
+        };
+
+        let intact = if entry.is_dir {
+            metadata.is_dir()
+        } else if entry.is_symlink {
+            metadata.file_type().is_symlink() && entry.symlink_target_matches(&file_path)?
+        } else if fast {
+            let modified = metadata
+                .modified()
+                .context(format_err!("can not get modified time for file {:?}", path))?;
+
+            // Without hashing only writes can be detected.
+            if metadata.len() == entry.len && repofile::times_match(modified, entry.modified, self.mtime_tolerance()) {
+                return Ok(Verification::Intact);
+            } else {
+                return Ok(Verification::Modified);
+            }
+        } else {
+            let hash = repofile::hash_path(&file_path).context(format_err!("can not hash file {:?}", path))?;
+
+            if entry.hash.as_ref() != Some(&hash) && !self.stat_matches(&path, entry)? {
+                return Ok(Verification::Modified);
+            }
+
+            entry.hash.as_ref() == Some(&hash)
+        };
+
+        if intact {
+            Ok(Verification::Intact)
+        } else {
+            Ok(Verification::Corrupted)
+        }
+    }
+
+    /// Whether size, modified time, changed time and inode of the file at
+    /// `path` still match the index. If they do not the file was written to
+    /// since it was added.
+    fn stat_matches<P: AsRef<Path> + Debug>(&self, path: P, entry: &RepoFile) -> Result<bool, Error> {
+        let metadata = match symlink_metadata(self.path.join(&path)) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+
+        let stat = entry
+            .compare_metadata(&metadata, self.mtime_tolerance())
+            .context(format_err!("can not compare metadata for file {:?}", path))?;
+
+        Ok(stat == StatMatch::Unchanged)
+    }
+
+    /// Adds all paths below `folder_path` that match the pathspec. Errors of
+    /// single paths do not stop the other paths from being added, they are
+    /// returned with the outcomes of the other paths instead.
+    fn add_folder<P: AsRef<Path> + Debug>(
+        &self,
+        folder_path: P,
+        pathspec: &Pathspec,
+        progress: &Progress,
+    ) -> Result<Vec<Result<AddOutcome, Error>>, Error> {
+        let repo_path = self.path.clone();
+        let data_path = self.get_data_path();
+        let (tx, rx) = unbounded();
+        let (result_tx, result_rx) = unbounded();
+
+        let worker = num_cpus::get();
+        let index = self.open_index()?;
+        let candidates = Arc::new(MoveCandidates::new(self.deleted_entries(&index)?));
+        let hardlinks = Arc::new(HardlinkHashes::default());
+        let index = Arc::new(index);
+        let writer = IndexWriter::new(Arc::clone(&index));
+        let barrier = Arc::new(Barrier::new(worker + 1));
+
+        for worker in 0..worker {
+            let rx = rx.clone();
+            let result_tx = result_tx.clone();
+            let repo_path = repo_path.clone();
+            let index = Arc::clone(&index);
+            let updates = writer.sender();
+            let candidates = Arc::clone(&candidates);
+            let hardlinks = Arc::clone(&hardlinks);
+            let progress = progress.clone();
+            let barrier = Arc::clone(&barrier);
+
+            thread::spawn(move || {
+                let repo = Repository::open(&repo_path).expect("can not open worker repository");
+
+                loop {
+                    let entry = rx.recv();
+                    debug!("worker {} received message", worker);
+
+                    if entry.is_err() {
+                        debug!("worker {} has ended", worker);
+                        break;
+                    }
+
+                    let result = repo.add_file(&index, &updates, &candidates, &hardlinks, entry.unwrap());
+                    progress.advance(1, result.as_ref().map(AddOutcome::bytes).unwrap_or(0));
+                    result_tx.send(result).expect("can not send add result");
+                }
+
+                debug!("worker thread {} is waiting", worker);
+                barrier.wait();
+            });
+        }
+
+        let ignore = self.ignore_filter()?;
+        let walker = WalkDir::new(folder_path)
+            .into_iter()
+            .filter_entry(|entry| !ignore.is_ignored(entry.path(), entry.file_type().is_dir()));
+
+        // Parent directories of matching paths get tracked as well so the
+        // tree can be recreated. This remembers which ones were already sent.
+        let mut directories = BTreeSet::new();
+
+        // Only the first path of every inode with multiple hardlinks is
+        // added by the workers. The others are added after the workers are
+        // done so they can reuse its hash.
+        let mut inodes = BTreeSet::new();
+        let mut hardlinked = Vec::new();
+
+        for entry in walker {
+            let entry = entry.unwrap();
+            let path = entry.path().to_path_buf();
+
+            if path == repo_path {
+                continue;
+            }
+
+            if path.starts_with(&data_path) {
+                continue;
+            }
+
+            if !pathspec.matches(self.strip_path(&path)) {
+                continue;
+            }
+
+            let mut parent = path.parent();
+            while let Some(directory) = parent {
+                if directory == repo_path || !directory.starts_with(&repo_path) {
+                    break;
+                }
+
+                if directories.insert(directory.to_path_buf()) {
+                    tx.send(directory.to_path_buf())
+                        .expect("can not send path");
+                }
+
+                parent = directory.parent();
+            }
+
+            if path.is_dir() && !directories.insert(path.clone()) {
+                continue;
+            }
+
+            if entry.file_type().is_file() {
+                let metadata = entry
+                    .metadata()
+                    .context(format_err!("can not get metadata for file {:?}", path))?;
+
+                if metadata.nlink() > 1 && !inodes.insert((metadata.dev(), metadata.ino())) {
+                    hardlinked.push(path);
+                    continue;
+                }
+            }
+
+            tx.send(path).expect("can not send path");
+        }
+
+        debug!("dropping tx channel");
+        drop(tx);
+
+        debug!("main thread is waiting");
+        barrier.wait();
+
+        let mut results = Vec::new();
+
+        let updates = writer.sender();
+        for path in hardlinked {
+            let result = self.add_file(&index, &updates, &candidates, &hardlinks, path);
+            progress.advance(1, result.as_ref().map(AddOutcome::bytes).unwrap_or(0));
+            results.push(result);
+        }
+        drop(updates);
+
+        let written = writer.finish().context("can not write added files to index")?;
+        debug!("wrote {} index updates", written);
+
+        while let Ok(result) = result_rx.try_recv() {
+            results.push(result);
+        }
+
+        Ok(results)
+    }
+
+    /// Turns a path given by the user into a path relative to the repository
+    /// root that is safe to use on disk. An empty path is the root itself.
+    /// Paths that leave the repository or point into `.syncust` are
+    /// rejected.
+    fn relative_path<P: AsRef<Path> + Debug>(&self, path: P) -> Result<PathBuf, Error> {
+        let mut stripped = self.strip_path(&path);
+        if stripped.is_absolute() {
+            if let Ok(root) = self.path.canonicalize() {
+                if let Ok(relative) = stripped.strip_prefix(&root) {
+                    stripped = relative.to_path_buf();
+                }
+            }
+        }
+
+        let mut out = PathBuf::new();
+        for component in stripped.components() {
+            match component {
+                Component::Normal(name) => out.push(name),
+                Component::CurDir => {}
+                _ => bail!("path {:?} is not inside the repository", path),
+            }
+        }
+
+        if self.path.join(&out).starts_with(self.get_data_path()) {
+            bail!("path {:?} is inside the repository data directory", path)
+        }
+
+        Ok(out)
+    }
+
+    fn strip_path<P: AsRef<Path> + Debug>(&self, path: P) -> PathBuf {
+        if path.as_ref().starts_with(&self.path) {
+            path.as_ref()
+                .strip_prefix(&self.path)
+                .expect("can not strip repo path")
+                .to_path_buf()
+        } else {
+            path.as_ref().to_path_buf()
+        }
+    }
+
+    fn add_file<P: AsRef<Path> + Debug>(
+        &self,
+        index: &Index,
+        updates: &Sender<Vec<IndexUpdate>>,
+        candidates: &MoveCandidates,
+        hardlinks: &HardlinkHashes,
+        file_path: P,
+    ) -> Result<AddOutcome, Error> {
+        if file_path.as_ref().starts_with(self.get_data_path()) {
+            bail!("can not add file that is inside the data dir")
+        }
+
+        debug!("add_file: adding file {:?}", file_path);
+
+        let start = PreciseTime::now();
+
+        let path = self.strip_path(&file_path);
+
+        trace!("add_file: path - {:?}", path);
+
+        debug!("add_file: checking if path is already tracked");
+        if index.contains(&path) {
+            return self.update_file(index, updates, hardlinks, &file_path, path);
+        }
+
+        debug!("add_file: checking if file was moved from a deleted path");
+        let mut file = self.read_file(hardlinks, &file_path)?;
+
+        let moved = match file.hash {
+            Some(ref hash) => candidates.take_by_hash(file.len, hash),
+            None => None,
+        };
+
+        // A moved file keeps its identity.
+        let source = match moved {
+            Some((source, old)) => {
+                file.uuid = old.uuid;
+                Some(source)
+            }
+            None => None,
+        };
+
+        let checking = PreciseTime::now();
+
+        let outcome = if source.is_some() {
+            AddOutcome::Moved(file.len)
+        } else {
+            AddOutcome::Added(file.len)
+        };
+
+        let mut update = vec![IndexUpdate::Set(path.clone(), file)];
+        if let Some(source) = source {
+            info!("detected move from {:?} to {:?}", source, path);
+            update.push(IndexUpdate::Delete(source));
+        }
+
+        updates
+            .send(update)
+            .map_err(|_| format_err!("index writer has stopped"))?;
+        let index = PreciseTime::now();
+
+        debug!(
+            "added file {:?}: checking - {:?}, index - {:?}",
+            file_path,
+            start.to(checking),
+            checking.to(index),
+        );
+
+        Ok(outcome)
+    }
+
+    /// Records a new version of a tracked path if its content or metadata
+    /// changed. The entry keeps its identity, the previous version stays in
+    /// the history.
+    fn update_file<P: AsRef<Path> + Debug>(
+        &self,
+        index: &Index,
+        updates: &Sender<Vec<IndexUpdate>>,
+        hardlinks: &HardlinkHashes,
+        file_path: P,
+        path: PathBuf,
+    ) -> Result<AddOutcome, Error> {
+        let old = index.get(&path)?;
+        let metadata = symlink_metadata(&file_path).context(format_err!("can not get metadata for file {:?}", file_path))?;
+
+        if old.compare_metadata(&metadata, self.mtime_tolerance())? == StatMatch::Unchanged {
+            if old.present {
+                remember_hardlink(hardlinks, &metadata, &old.hash);
+                return Ok(AddOutcome::Unchanged);
+            }
+
+            if old.is_corrupted_copy() {
+                warn!("not adding {:?}, its content is corrupted", path);
+                return Ok(AddOutcome::Unchanged);
+            }
+        }
+
+        let mut file = self.read_file(hardlinks, &file_path)?;
+
+        let content_changed = file.hash != old.hash || file.symlink_target != old.symlink_target || file.is_dir != old.is_dir;
+        let metadata_changed = file.permissions != old.permissions || file.uid != old.uid || file.gid != old.gid
+            || file.xattrs != old.xattrs;
+
+        let (update, outcome) = if content_changed || metadata_changed || !old.present {
+            debug!("updating changed file {:?}", path);
+            file.uuid = old.uuid;
+
+            let len = file.len;
+            (IndexUpdate::Set(path, file), AddOutcome::Updated(len))
+        } else {
+            // Only the stat data changed so remember the new one to skip
+            // hashing the file next time.
+            let mut old = old;
+            old.refresh_metadata(&metadata)?;
+
+            (IndexUpdate::SetLocal(path, old), AddOutcome::Unchanged)
+        };
+
+        updates
+            .send(vec![update])
+            .map_err(|_| format_err!("index writer has stopped"))?;
+
+        Ok(outcome)
+    }
+
+    /// Creates the entry for a path on disk with the attributes selected in
+    /// the settings. Reuses the hash of other hardlinks to the same inode.
+    fn read_file<P: AsRef<Path> + Debug>(&self, hardlinks: &HardlinkHashes, file_path: P) -> Result<RepoFile, Error> {
+        let metadata = symlink_metadata(&file_path).context(format_err!("can not get metadata for file {:?}", file_path))?;
+        let known_hash = if metadata.is_file() && metadata.nlink() > 1 {
+            hardlinks.get(metadata.dev(), metadata.ino())
+        } else {
+            None
+        };
+
+        debug!("add_file: creating repo_file from file_path");
+        let mut file = RepoFile::from_path_with_hash(&file_path, known_hash).context(format_err!(
+            "can not
+        create file from path {:?}",
+            file_path
+        ))?;
+
+        file.capture_attributes(&file_path, self.settings.map_owner_names, self.xattr_filter())?;
+        if let Some(ref hash) = file.hash {
+            self.store_object(&file_path, hash)?;
+        }
+
+        if file.is_hardlink() {
+            if let Some(ref hash) = file.hash {
+                hardlinks.insert(file.device, file.inode, hash.clone());
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Returns the tracked entries that should be present but whose path does
+    /// not exist on disk anymore.
+    fn deleted_entries(&self, index: &Index) -> Result<Vec<(PathBuf, RepoFile)>, Error> {
+        let deleted = index
+            .entries()?
+            .into_iter()
+            .filter(|&(ref path, ref entry)| entry.present && symlink_metadata(self.path.join(path)).is_err())
+            .collect();
+
+        Ok(deleted)
+    }
+
+    fn clone_local<P: AsRef<Path> + Debug>(&self, source_path: P) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let src_repo = Repository::open(source_path).context("can not open source repository")?;
+
+        if !src_repo.is_inialized() {
+            bail!("source repository is not initialized")
+        }
+
+        let src_index = src_repo.open_index().context("can not open source repository index")?;
+
+        let index = self.open_index().context("can not open repository index")?;
+
+        // Only the index is cloned so none of the content is present yet.
+        let sequence = src_index.last_sequence();
+        index.import(&src_index)?;
+
+        // Later syncs only need the changes the source made after the clone.
+        index.set_checkpoint(&src_repo.settings.uuid.to_string(), sequence)?;
+
+        Ok(())
+    }
+
+    fn xattr_filter(&self) -> XattrFilter {
+        XattrFilter {
+            xattrs: self.settings.preserve_xattrs,
+            acls: self.settings.preserve_acls,
+        }
+    }
+
+    /// Restores the owner and extended attributes recorded in `entry` on the
+    /// file at `file_path` as far as the settings allow. Has to run before
+    /// the permissions are set as changing the owner clears the setuid and
+    /// setgid bits.
+    fn apply_attributes<P: AsRef<Path> + Debug>(&self, entry: &RepoFile, file_path: P) -> Result<(), Error> {
+        if self.settings.preserve_ownership {
+            let mut uid = entry.uid;
+            let mut gid = entry.gid;
+
+            if self.settings.map_owner_names {
+                if let Some(mapped) = entry.owner.as_ref().and_then(|owner| attributes::user_id(owner)) {
+                    uid = mapped;
+                }
+
+                if let Some(mapped) = entry.group.as_ref().and_then(|group| attributes::group_id(group)) {
+                    gid = mapped;
+                }
+            }
+
+            attributes::set_owner(&file_path, uid, gid)?;
+        }
+
+        if !self.xattr_filter().is_empty() {
+            attributes::write_xattrs(&file_path, &entry.xattrs)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the recorded modified time and, with `restore_atime`, the
+    /// recorded access time on a materialized file. Has to be called after
+    /// the content is written and, for directories, after all their children
+    /// are created.
+    fn apply_times<P: AsRef<Path> + Debug>(&self, entry: &RepoFile, file_path: P) -> Result<(), Error> {
+        let accessed = if self.settings.restore_atime {
+            Some(entry.accessed)
+        } else {
+            None
+        };
+
+        attributes::set_times(&file_path, entry.modified, accessed)
+    }
+
+    /// Transfer options from the settings, to be adjusted per invocation.
+    pub fn scheduler_options(&self) -> SchedulerOptions {
+        SchedulerOptions {
+            concurrency: self.settings.transfer_concurrency.unwrap_or_else(num_cpus::get),
+            bandwidth_limit: self.settings.transfer_bandwidth_limit,
+            priority: self.settings.transfer_priority,
+            retries: self.settings.transfer_retries,
+            remotes: self.settings.remote_limits.clone(),
+        }
+    }
+
+    /// Bandwidth for single transfers outside of the scheduler.
+    fn bandwidth(&self) -> Bandwidth {
+        Bandwidth::limited(self.settings.transfer_bandwidth_limit)
+    }
+
+    fn mtime_tolerance(&self) -> Duration {
+        Duration::from_millis(self.settings.mtime_tolerance_ms)
+    }
+
+    pub fn pathspec<P: AsRef<Path>>(&self, patterns: &[P]) -> Result<Pathspec, Error> {
+        Pathspec::new(&self.path, patterns)
+    }
+
+    fn ignore_filter(&self) -> Result<IgnoreFilter, Error> {
+        let filter = IgnoreFilter::new(&self.path, &self.settings.ignore).context("can not create ignore filter")?;
+
+        Ok(filter)
+    }
+
+    fn write_settings(&self) -> Result<(), Error> {
+        let settings_path = self.get_settings_path();
+        let settings_file = File::create(&settings_path).context(format_err!(
+            "can not create settings file {:?}",
+            settings_path
+        ))?;
+
+        to_writer(&settings_file, &self.settings).context("can not serialize repository settings to settings file")?;
+
+        Ok(())
+    }
+
+    fn load_settings(&mut self) -> Result<(), Error> {
+        let settings_file = File::open(self.get_settings_path()).context("can not open settings file")?;
+        let settings: Settings = from_reader(settings_file).context("can not deserialize settings")?;
+
+        scheduler::check_bandwidth_limit("transfer_bandwidth_limit", settings.transfer_bandwidth_limit)?;
+        for (remote, limits) in &settings.remote_limits {
+            scheduler::check_bandwidth_limit(&format!("bandwidth_limit of remote {}", remote), limits.bandwidth_limit)?;
+        }
+
+        self.settings = settings;
+
+        if self.settings.uuid.is_nil() {
+            self.settings.uuid = Uuid::new_v4();
+            self.write_settings().context("can not write generated repository uuid")?;
+        }
+
+        Ok(())
+    }
+
+    /// Brings the index of a repository written by an older version to the
+    /// current format. Formats that can not be converted are refused instead
+    /// of being misread.
+    fn upgrade_format(&mut self) -> Result<(), Error> {
+        match self.settings.version {
+            FORMAT_VERSION => return Ok(()),
+            1 => {
+                info!("migrating index of {:?} from format version 1 to {}", self.path, FORMAT_VERSION);
+                Index::migrate_v1(self.get_index_path(), &self.path).context("can not migrate index")?;
+            }
+            version if version > FORMAT_VERSION => bail!(
+                "repository format version {} is newer than the supported version {}, update syncust to open it",
+                version,
+                FORMAT_VERSION
+            ),
+            version => bail!(
+                "repository format version {} can not be migrated to version {}, clone the repository again",
+                version,
+                FORMAT_VERSION
+            ),
+        }
+
+        self.settings.version = FORMAT_VERSION;
+        self.write_settings().context("can not write migrated format version")?;
+
+        Ok(())
+    }
+
+    fn write_fsck_checkpoint<P: AsRef<Path> + Debug>(&self, last_path: P) -> Result<(), Error> {
+        let checkpoint_path = self.get_fsck_checkpoint_path();
+        let checkpoint_file = File::create(&checkpoint_path).context(format_err!(
+            "can not create fsck checkpoint file {:?}",
+            checkpoint_path
+        ))?;
+
+        let checkpoint = FsckCheckpoint {
+            last_path: last_path.as_ref().to_path_buf(),
+        };
+
+        to_writer(&checkpoint_file, &checkpoint).context("can not serialize fsck checkpoint to checkpoint file")?;
+
+        Ok(())
+    }
+
+    fn load_fsck_checkpoint(&self) -> Result<Option<FsckCheckpoint>, Error> {
+        let checkpoint_path = self.get_fsck_checkpoint_path();
+        if !checkpoint_path.exists() {
+            return Ok(None);
+        }
+
+        let checkpoint_file = File::open(&checkpoint_path).context("can not open fsck checkpoint file")?;
+        let checkpoint: FsckCheckpoint = from_reader(checkpoint_file).context("can not deserialize fsck checkpoint")?;
+
+        Ok(Some(checkpoint))
+    }
+
+    fn remove_fsck_checkpoint(&self) -> Result<(), Error> {
+        let checkpoint_path = self.get_fsck_checkpoint_path();
+        if checkpoint_path.exists() {
+            remove_file(&checkpoint_path).context(format_err!(
+                "can not remove fsck checkpoint file {:?}",
+                checkpoint_path
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    fn progress(&self, operation: &'static str) -> Progress {
+        Progress::new(operation, self.progress.clone())
+    }
+
+    fn open_index(&self) -> Result<Index, Error> {
+        Index::open(self.get_index_path(), self.settings.uuid)
+    }
+
+    fn get_data_path(&self) -> PathBuf {
+        self.path.clone().join(".syncust")
+    }
+
+    fn get_index_path(&self) -> PathBuf {
+        self.get_data_path().join("index.rocksdb")
+    }
+
+    /// Objects are stored under their hash, in `sublayers` levels of
+    /// directories named after the leading pairs of hex digits of the hash.
+    fn get_objects_path(&self) -> PathBuf {
+        self.get_data_path().join("objects")
+    }
+
+    fn get_object_path(&self, hash: &str) -> PathBuf {
+        let mut path = self.get_objects_path();
+
+        for layer in 0..self.settings.sublayers {
+            match hash.get(layer * 2..layer * 2 + 2) {
+                Some(name) => path.push(name),
+                None => break,
+            }
+        }
+
+        path.join(hash)
+    }
+
+    /// Partial files of transfers that have not finished yet.
+    fn get_tmp_path(&self) -> PathBuf {
+        self.get_data_path().join("tmp")
+    }
+
+    fn get_settings_path(&self) -> PathBuf {
+        self.get_data_path().join("settings.json")
+    }
+
+    fn get_fsck_checkpoint_path(&self) -> PathBuf {
+        self.get_data_path().join("fsck_checkpoint.json")
+    }
+
+    fn is_inialized(&self) -> bool {
+        self.get_data_path().exists()
+    }
+
+    pub fn debug_tracked_files(&self, format: OutputFormat) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+
+        match format {
+            OutputFormat::Text => index.debug_tracked_files()?,
+            OutputFormat::Json => {
+                let entries = index
+                    .entries()?
+                    .into_iter()
+                    .map(|(path, file)| IndexEntry {
+                        path: path,
+                        file: file,
+                    })
+                    .collect::<Vec<_>>();
+
+                output::print_json(&entries)?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn debug_journal(&self, since: u64, remote: Option<&str>, format: OutputFormat) -> Result<(), Error> {
+        if !self.is_inialized() {
+            Err(RepositoryError::NotInitialized)?
+        }
+
+        let index = self.open_index()?;
+
+        let since = match remote {
+            Some(remote) => index.checkpoint(remote)?,
+            None => since,
+        };
+
+        match format {
+            OutputFormat::Text => for entry in index.changes_since(since)? {
+                println!("{}", entry?);
+            },
+            OutputFormat::Json => {
+                let entries = index
+                    .changes_since(since)?
+                    .collect::<Result<Vec<_>, Error>>()?;
+
+                output::print_json(&entries)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Records the hash of a hardlink that is known without hashing it so the
+/// other links to the same inode do not have to be hashed either.
+fn remember_hardlink(hardlinks: &HardlinkHashes, metadata: &Metadata, hash: &Option<String>) {
+    if let Some(ref hash) = *hash {
+        if metadata.is_file() && metadata.nlink() > 1 {
+            hardlinks.insert(metadata.dev(), metadata.ino(), hash.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{
+        Digest,
+        Sha256,
+    };
+    use std::fs::{
+        read,
+        read_link,
+        write,
+    };
+    use std::os::unix::fs::symlink;
+    use testdir::TestDir;
+
+    /// Creates an initialized repository in a fresh test directory, which
+    /// has to be kept alive for as long as the repository is used.
+    fn repository(name: &str) -> (TestDir, Repository) {
+        let dir = TestDir::new(&format!("repository-{}", name));
+        Repository::default().with_path(&*dir).init().unwrap();
+        let repository = Repository::open(&*dir).unwrap();
+
+        (dir, repository)
+    }
+
+    /// Writes the files and adds everything in the repository.
+    fn add_files(repository: &mut Repository, files: &[(&str, &[u8])]) {
+        for &(path, content) in files {
+            let file_path = repository.path.join(path);
+            create_dir_all(file_path.parent().unwrap()).unwrap();
+            write(&file_path, content).unwrap();
+        }
+
+        let report = repository.add(Vec::<PathBuf>::new()).unwrap();
+        assert_eq!(report.failed_count, 0);
+    }
+
+    fn is_tracked<P: AsRef<Path> + Debug>(repository: &Repository, path: P) -> bool {
+        repository.open_index().unwrap().contains(path)
+    }
+
+    #[test]
+    fn fsck_records_unreadable_paths_and_continues() {
+        let (_dir, mut repository) = repository("fsck-errors");
+        add_files(&mut repository, &[("a.txt", b"a"), ("b.txt", b"b")]);
+
+        // A directory in place of the file can not be hashed.
+        remove_file(repository.path.join("a.txt")).unwrap();
+        create_dir_all(repository.path.join("a.txt")).unwrap();
+
+        let report = repository.fsck(&FsckOptions::default()).unwrap();
+
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].path, PathBuf::from("a.txt"));
+        assert!(report.corrupted_paths.is_empty());
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn status_hashing_records_unreadable_paths_and_continues() {
+        let (_dir, mut repository) = repository("status-errors");
+        add_files(&mut repository, &[("a.txt", b"a"), ("b.txt", b"b")]);
+
+        let index = repository.open_index().unwrap();
+        let entries = ["a.txt", "b.txt"]
+            .iter()
+            .map(|path| {
+                let metadata = symlink_metadata(repository.path.join(path)).unwrap();
+                (PathBuf::from(path), index.get(path).unwrap(), metadata)
+            })
+            .collect();
+
+        // A directory in place of the file can not be hashed.
+        remove_file(repository.path.join("a.txt")).unwrap();
+        create_dir_all(repository.path.join("a.txt")).unwrap();
+
+        let mut results = repository.hash_entries(entries);
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].3.is_err());
+        assert_eq!(results[1].3.as_ref().ok(), results[1].1.hash.as_ref());
+    }
+
+    #[test]
+    fn fsck_verifies_and_repairs_the_object_store() {
+        let (_dir, mut repository) = repository("fsck-objects");
+        add_files(&mut repository, &[("a.txt", b"a"), ("b.txt", b"b")]);
+
+        let index = repository.open_index().unwrap();
+        let corrupted = index.get("a.txt").unwrap().hash.unwrap();
+        let missing = index.get("b.txt").unwrap().hash.unwrap();
+
+        let corrupted_path = repository.get_object_path(&corrupted);
+        set_permissions(&corrupted_path, Permissions::from_mode(0o644)).unwrap();
+        write(&corrupted_path, b"x").unwrap();
+        remove_file(repository.get_object_path(&missing)).unwrap();
+
+        let report = repository.fsck(&FsckOptions::default()).unwrap();
+
+        assert!(report.corrupted_objects.contains(&corrupted));
+        assert!(report.missing_objects.contains(&missing));
+        assert!(!report.is_clean());
+
+        let options = FsckOptions {
+            repair: true,
+            ..FsckOptions::default()
+        };
+        let report = repository.fsck(&options).unwrap();
+
+        assert_eq!(report.repaired_objects.len(), 2);
+        assert_eq!(read(&corrupted_path).unwrap(), b"a");
+        assert_eq!(read(repository.get_object_path(&missing)).unwrap(), b"b");
+        assert!(repository.fsck(&FsckOptions::default()).unwrap().is_clean());
+    }
+
+    #[test]
+    fn corrupted_copies_found_by_scrub_are_not_present() {
+        let (_dir, mut repository) = repository("scrub-corrupted");
+        add_files(&mut repository, &[("a.txt", b"a")]);
+
+        // An index entry with other content and the same stat data looks
+        // exactly like a copy that got corrupted on disk.
+        let index = repository.open_index().unwrap();
+        let mut entry = index.get("a.txt").unwrap();
+        entry.hash = Some(format!("{:x}", Sha256::digest(b"other")));
+        index.set("a.txt", &entry).unwrap();
+
+        let report = repository.scrub(&ScrubOptions::default()).unwrap();
+        assert!(report.corrupted_paths.contains(Path::new("a.txt")));
+        assert!(!index.get("a.txt").unwrap().present);
+
+        let status = repository.status(&Pathspec::default()).unwrap();
+        assert!(status.corrupted_paths.contains(Path::new("a.txt")));
+        assert_eq!(status.present_count, 0);
+
+        add_files(&mut repository, &[]);
+        assert_eq!(index.get("a.txt").unwrap().hash, entry.hash);
+
+        let report = repository.fsck(&FsckOptions::default()).unwrap();
+        assert!(report.corrupted_paths.contains(Path::new("a.txt")));
+    }
+
+    #[test]
+    fn add_does_not_take_other_content_for_a_move() {
+        let (_dir, mut repository) = repository("move-other-content");
+        add_files(&mut repository, &[("a.txt", b"aaaa")]);
+
+        let old = repository.open_index().unwrap().get("a.txt").unwrap();
+        remove_file(repository.path.join("a.txt")).unwrap();
+
+        // Same length and modified time as the deleted file, but a new inode
+        // with other content.
+        let new_path = repository.path.join("b.txt");
+        write(&new_path, b"bbbb").unwrap();
+        attributes::set_times(&new_path, old.modified, None).unwrap();
+
+        let report = repository.add(Vec::<PathBuf>::new()).unwrap();
+        assert_eq!(report.moved_count, 0);
+
+        let new = repository.open_index().unwrap().get("b.txt").unwrap();
+        assert_eq!(new.hash, Some(format!("{:x}", Sha256::digest(b"bbbb"))));
+        assert_ne!(new.uuid, old.uuid);
+    }
+
+    #[test]
+    fn add_keeps_the_identity_of_renamed_files() {
+        let (_dir, mut repository) = repository("move-rename");
+        add_files(&mut repository, &[("a.txt", b"aaaa")]);
+
+        let old = repository.open_index().unwrap().get("a.txt").unwrap();
+        rename(repository.path.join("a.txt"), repository.path.join("b.txt")).unwrap();
+
+        let report = repository.add(Vec::<PathBuf>::new()).unwrap();
+        assert_eq!(report.moved_count, 1);
+
+        let index = repository.open_index().unwrap();
+        assert!(!index.contains("a.txt"));
+        assert_eq!(index.get("b.txt").unwrap().uuid, old.uuid);
+    }
+
+    #[test]
+    fn remove_resolves_paths_against_the_root() {
+        let (_dir, mut repository) = repository("remove");
+        add_files(&mut repository, &[("a.txt", b"a"), ("dir/b.txt", b"b"), ("dir/c.txt", b"c")]);
+
+        let absolute = repository.path.join("dir/c.txt");
+        repository.remove(vec![PathBuf::from("dir/b.txt"), absolute], false).unwrap();
+
+        assert!(!repository.path.join("dir/b.txt").exists());
+        assert!(!repository.path.join("dir/c.txt").exists());
+        assert!(!is_tracked(&repository, "dir/b.txt"));
+        assert!(!is_tracked(&repository, "dir/c.txt"));
+        assert!(is_tracked(&repository, "a.txt"));
+    }
+
+    #[test]
+    fn remove_refuses_the_root_and_the_data_directory() {
+        let (_dir, mut repository) = repository("remove-root");
+        add_files(&mut repository, &[("a.txt", b"a")]);
+
+        let root = repository.path.clone();
+        for path in &[PathBuf::new(), PathBuf::from("."), root.clone(), PathBuf::from(".syncust"), PathBuf::from("../a.txt")] {
+            assert!(repository.remove(vec![path], false).is_err(), "{:?}", path);
+        }
+
+        assert!(root.join("a.txt").exists());
+        assert!(root.join(".syncust").is_dir());
+        assert!(is_tracked(&repository, "a.txt"));
+    }
+
+    #[test]
+    fn rename_into_a_directory_of_the_repository() {
+        let (_dir, mut repository) = repository("rename");
+        add_files(&mut repository, &[("a.txt", b"a"), ("dir/b.txt", b"b")]);
+
+        repository.rename("a.txt", "dir").unwrap();
+
+        assert!(!repository.path.join("a.txt").exists());
+        assert_eq!(read(repository.path.join("dir/a.txt")).unwrap(), b"a");
+        assert!(!is_tracked(&repository, "a.txt"));
+        assert!(is_tracked(&repository, "dir/a.txt"));
+    }
+
+    #[test]
+    fn rename_refuses_moving_into_itself() {
+        let (_dir, mut repository) = repository("rename-into-itself");
+        add_files(&mut repository, &[("dir/sub/a.txt", b"a")]);
+
+        assert!(repository.rename("dir", "dir/sub").is_err());
+        assert!(repository.rename("", "dir").is_err());
+        assert!(repository.rename(".syncust", "dir").is_err());
+
+        assert!(repository.path.join("dir/sub/a.txt").exists());
+        assert!(is_tracked(&repository, "dir/sub/a.txt"));
+    }
+
+    #[test]
+    fn fsck_repair_keeps_edits_that_are_not_added() {
+        let (_dir, mut repository) = repository("fsck-repair");
+        add_files(&mut repository, &[("a.txt", b"same"), ("b.txt", b"same")]);
+
+        write(repository.path.join("b.txt"), b"edit").unwrap();
+
+        let options = FsckOptions {
+            repair: true,
+            repair_from_duplicates: true,
+            ..FsckOptions::default()
+        };
+        let report = repository.fsck(&options).unwrap();
+
+        assert!(report.modified_paths.contains(Path::new("b.txt")));
+        assert!(report.corrupted_paths.is_empty());
+        assert!(report.repaired_paths.is_empty());
+        assert_eq!(read(repository.path.join("b.txt")).unwrap(), b"edit");
+    }
+
+    #[test]
+    fn local_bookkeeping_is_not_journaled() {
+        let (_dir, mut repository) = repository("local-bookkeeping");
+        add_files(&mut repository, &[("a.txt", b"a"), ("b.txt", b"b")]);
+
+        let sequence = repository.open_index().unwrap().last_sequence();
+        remove_file(repository.path.join("b.txt")).unwrap();
+
+        let options = FsckOptions {
+            repair: true,
+            ..FsckOptions::default()
+        };
+        repository.fsck(&options).unwrap();
+        repository.scrub(&ScrubOptions::default()).unwrap();
+        repository.status(&Pathspec::default()).unwrap();
+
+        let index = repository.open_index().unwrap();
+        assert_eq!(index.last_sequence(), sequence);
+        assert!(index.get("a.txt").unwrap().last_verified.is_some());
+        assert!(!index.get("b.txt").unwrap().present);
+    }
+
+    #[test]
+    fn open_rejects_a_zero_bandwidth_limit() {
+        let (dir, mut repository) = repository("zero-bandwidth");
+        repository.settings.transfer_bandwidth_limit = Some(0);
+        repository.write_settings().unwrap();
+
+        assert!(Repository::open(&*dir).is_err());
+    }
+
+    #[test]
+    fn json_output_shows_uuids_as_strings() {
+        let (_dir, mut repository) = repository("json-uuids");
+        add_files(&mut repository, &[("a.txt", b"a")]);
+
+        let index = repository.open_index().unwrap();
+        let entry = index.get("a.txt").unwrap();
+        let journal_entry = index.changes_since(0).unwrap().next().unwrap().unwrap();
+        let version = index.history("a.txt").unwrap().remove(0);
+
+        let uuid = entry.uuid.to_string();
+        let origin = repository.settings.uuid.to_string();
+
+        let file = serde_json::to_value(&entry).unwrap();
+        assert_eq!(file["uuid"].as_str(), Some(uuid.as_str()));
+
+        let change = serde_json::to_value(&journal_entry).unwrap();
+        assert_eq!(change["origin"].as_str(), Some(origin.as_str()));
+        assert_eq!(change["change"]["Set"]["uuid"].as_str(), Some(uuid.as_str()));
+
+        let version = serde_json::to_value(&version).unwrap();
+        assert_eq!(version["origin"].as_str(), Some(origin.as_str()));
+        assert_eq!(version["uuid"].as_str(), Some(uuid.as_str()));
+    }
+
+    #[test]
+    fn restore_brings_back_replaced_content_from_the_object_store() {
+        let (_dir, mut repository) = repository("restore");
+        add_files(&mut repository, &[("a.txt", b"first")]);
+        add_files(&mut repository, &[("a.txt", b"second version")]);
+
+        let versions = repository.history("a.txt").unwrap().versions;
+        assert_eq!(versions.len(), 2);
+
+        repository
+            .restore("a.txt", &RestoreVersion::Sequence(versions[0].sequence))
+            .unwrap();
+
+        assert_eq!(read(repository.path.join("a.txt")).unwrap(), b"first");
+        assert_eq!(repository.open_index().unwrap().get("a.txt").unwrap().hash, versions[0].hash);
+    }
+
+    #[test]
+    fn restore_skips_a_corrupted_object_for_a_tracked_copy() {
+        let (_dir, mut repository) = repository("restore-corrupted-object");
+        add_files(&mut repository, &[("a.txt", b"first"), ("b.txt", b"first")]);
+        add_files(&mut repository, &[("a.txt", b"second version")]);
+
+        let versions = repository.history("./a.txt").unwrap().versions;
+        let object_path = repository.get_object_path(versions[0].hash.as_ref().unwrap());
+        set_permissions(&object_path, Permissions::from_mode(0o644)).unwrap();
+        write(&object_path, b"garbage").unwrap();
+
+        repository
+            .restore("./a.txt", &RestoreVersion::Sequence(versions[0].sequence))
+            .unwrap();
+
+        assert_eq!(read(repository.path.join("a.txt")).unwrap(), b"first");
+        assert!(repository.history("../a.txt").is_err());
+    }
+
+    #[test]
+    fn history_records_symlink_and_permission_changes() {
+        let (_dir, mut repository) = repository("history-metadata");
+        symlink("a.txt", repository.path.join("link")).unwrap();
+        add_files(&mut repository, &[("a.txt", b"a")]);
+
+        remove_file(repository.path.join("link")).unwrap();
+        symlink("b.txt", repository.path.join("link")).unwrap();
+        set_permissions(repository.path.join("a.txt"), Permissions::from_mode(0o600)).unwrap();
+        add_files(&mut repository, &[]);
+
+        assert_eq!(repository.history("a.txt").unwrap().versions.len(), 2);
+
+        let versions = repository.history("link").unwrap().versions;
+        assert_eq!(versions.len(), 2);
+
+        repository
+            .restore("link", &RestoreVersion::Sequence(versions[0].sequence))
+            .unwrap();
+
+        assert_eq!(read_link(repository.path.join("link")).unwrap(), Path::new("a.txt"));
+        assert_eq!(repository.history("link").unwrap().versions.len(), 3);
+    }
+
+    #[test]
+    fn restore_atime_brings_back_the_recorded_access_time() {
+        let (_dir, mut repository) = repository("restore-atime");
+        repository.settings.restore_atime = true;
+
+        let file_path = repository.path.join("a.txt");
+        let now = SystemTime::now();
+        let accessed = now - Duration::from_secs(10);
+        write(&file_path, b"first").unwrap();
+        attributes::set_times(&file_path, now - Duration::from_secs(1000), Some(accessed)).unwrap();
+        add_files(&mut repository, &[]);
+        add_files(&mut repository, &[("a.txt", b"second version")]);
+
+        let versions = repository.history("a.txt").unwrap().versions;
+        repository
+            .restore("a.txt", &RestoreVersion::Sequence(versions[0].sequence))
+            .unwrap();
+
+        let metadata = symlink_metadata(&file_path).unwrap();
+        assert_eq!(metadata.accessed().unwrap(), accessed);
+        assert_ne!(metadata.accessed().unwrap(), metadata.modified().unwrap());
+    }
+
+    #[test]
+    fn restore_refuses_to_overwrite_edits_that_are_not_added() {
+        let (_dir, mut repository) = repository("restore-edits");
+        add_files(&mut repository, &[("a.txt", b"first")]);
+        add_files(&mut repository, &[("a.txt", b"second version")]);
+
+        let versions = repository.history("a.txt").unwrap().versions;
+        write(repository.path.join("a.txt"), b"third").unwrap();
+
+        assert!(
+            repository
+                .restore("a.txt", &RestoreVersion::Sequence(versions[0].sequence))
+                .is_err()
+        );
+        assert_eq!(read(repository.path.join("a.txt")).unwrap(), b"third");
     }
 }
@@ -1,34 +1,85 @@
+use failure::Error;
+use fsck::PathError;
+use output::serialize_paths;
 use std::collections::BTreeSet;
 use std::fmt;
 use std::path::PathBuf;
 
-#[derive(Debug, Default)]
+#[derive(Serialize, Debug, Default)]
 pub struct RepoStatus {
     pub paths_count: usize,
-    pub untracked_paths: BTreeSet<PathBuf>,
+    pub present_count: usize,
+    pub present_bytes: u64,
+    /// Bytes the present paths take up on disk. Smaller than
+    /// `present_bytes` when there are sparse files.
+    pub present_allocated_bytes: u64,
+    #[serde(serialize_with = "serialize_paths")]
+    pub missing_paths: BTreeSet<PathBuf>,
+    pub missing_bytes: u64,
+    #[serde(serialize_with = "serialize_paths")]
+    pub deleted_paths: BTreeSet<PathBuf>,
+    pub deleted_bytes: u64,
+    #[serde(serialize_with = "serialize_paths")]
     pub changed_paths: BTreeSet<PathBuf>,
+    pub changed_bytes: u64,
+    /// Files whose content was found to be corrupted by scrub or fsck and
+    /// that were not repaired yet. They do not count as present.
+    #[serde(serialize_with = "serialize_paths")]
+    pub corrupted_paths: BTreeSet<PathBuf>,
+    pub corrupted_bytes: u64,
+    #[serde(serialize_with = "serialize_paths")]
+    pub untracked_paths: BTreeSet<PathBuf>,
+    pub untracked_bytes: u64,
+    /// Paths that could not be checked, for example because they can not be
+    /// read. The other paths are still reported.
+    pub errors: Vec<PathError>,
+}
+
+impl RepoStatus {
+    /// Records that `path` could not be checked. The status continues with
+    /// the other paths.
+    pub fn record_error(&mut self, path: PathBuf, err: &Error) {
+        self.errors.push(PathError::new(path, err));
+    }
 }
 
 impl fmt::Display for RepoStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Paths Tracked: {}", self.paths_count)?;
+        write!(
+            f,
+            "\nPaths Present: {} ({} bytes, {} bytes allocated)",
+            self.present_count,
+            self.present_bytes,
+            self.present_allocated_bytes
+        )?;
 
-        if !self.untracked_paths.is_empty() {
-            let paths = self.untracked_paths
-                .iter()
-                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+        write_paths(f, "Missing Paths (not fetched)", &self.missing_paths, self.missing_bytes)?;
+        write_paths(f, "Deleted Paths", &self.deleted_paths, self.deleted_bytes)?;
+        write_paths(f, "Changed Paths", &self.changed_paths, self.changed_bytes)?;
+        write_paths(f, "Corrupted Paths", &self.corrupted_paths, self.corrupted_bytes)?;
+        write_paths(f, "Untracked Paths", &self.untracked_paths, self.untracked_bytes)?;
 
-            write!(f, "\nUntracked Paths:\n{}", paths)?;
-        }
-
-        if !self.changed_paths.is_empty() {
-            let paths = self.changed_paths
+        if !self.errors.is_empty() {
+            let errors = self.errors
                 .iter()
-                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+                .fold(String::new(), |acc, x| format!("{}\t{:?}: {}\n", acc, x.path, x.error));
 
-            write!(f, "\nChanged Paths:\n{}", paths)?;
+            write!(f, "\nErrors:\n{}", errors)?;
         }
 
         Ok(())
     }
 }
+
+fn write_paths(f: &mut fmt::Formatter, title: &str, paths: &BTreeSet<PathBuf>, bytes: u64) -> fmt::Result {
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let paths = paths
+        .iter()
+        .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+    write!(f, "\n{} ({} bytes):\n{}", title, bytes, paths)
+}
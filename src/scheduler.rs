@@ -0,0 +1,391 @@
+use crossbeam_channel::unbounded;
+use failure::Error;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Barrier,
+    Condvar,
+    Mutex,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use transfer;
+
+/// Name of the source for content that is copied from other paths of the
+/// same repository. Limits configured for it in `remote_limits` apply to
+/// those copies.
+pub const LOCAL_REMOTE: &str = "local";
+
+/// Delay before the first retry of a failed transfer. It doubles for every
+/// further retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Order in which queued transfers are started.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferPriority {
+    /// Many small files finish early, useful to get a usable tree fast.
+    SmallestFirst,
+    LargestFirst,
+    /// In path order, useful when the tree is used while it is restored.
+    Path,
+}
+
+impl Default for TransferPriority {
+    fn default() -> TransferPriority {
+        TransferPriority::SmallestFirst
+    }
+}
+
+impl FromStr for TransferPriority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TransferPriority, Error> {
+        match s {
+            "smallest_first" => Ok(TransferPriority::SmallestFirst),
+            "largest_first" => Ok(TransferPriority::LargestFirst),
+            "path" => Ok(TransferPriority::Path),
+            _ => bail!("unknown transfer priority {}", s),
+        }
+    }
+}
+
+/// Refuses a bandwidth limit of zero, which would stall every transfer.
+/// Transfers are not limited when the limit is left out.
+pub fn check_bandwidth_limit(name: &str, limit: Option<u64>) -> Result<(), Error> {
+    if limit == Some(0) {
+        bail!("{} has to be at least 1 byte per second, leave it out to not limit transfers", name)
+    }
+
+    Ok(())
+}
+
+/// Limits for the transfers from a single remote. Unset limits fall back to
+/// the global ones.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RemoteLimits {
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    /// Bytes per second.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedulerOptions {
+    /// Transfers running at the same time over all remotes.
+    pub concurrency: usize,
+    /// Bytes per second over all remotes.
+    pub bandwidth_limit: Option<u64>,
+    pub priority: TransferPriority,
+    /// How often a failed transfer is tried again. Retries continue from
+    /// the partial file of the failed attempt.
+    pub retries: u32,
+    pub remotes: BTreeMap<String, RemoteLimits>,
+}
+
+#[derive(Debug)]
+pub struct TransferJob {
+    pub remote: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub hash: String,
+    pub len: u64,
+}
+
+/// Caps the rate of everything that passes through it. Shared by all
+/// transfers the cap applies to.
+#[derive(Debug)]
+struct RateLimiter {
+    bytes_per_second: u64,
+    state: Mutex<RateState>,
+}
+
+#[derive(Debug)]
+struct RateState {
+    started: Instant,
+    consumed: u64,
+}
+
+impl RateLimiter {
+    /// `bytes_per_second` has to be greater than zero, limits from the
+    /// settings and the command line are checked by `check_bandwidth_limit`.
+    fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second: bytes_per_second,
+            state: Mutex::new(RateState {
+                started: Instant::now(),
+                consumed: 0,
+            }),
+        }
+    }
+
+    /// Blocks until `bytes` more fit into the rate.
+    fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().expect("rate limiter lock is poisoned");
+
+            // Idle time does not save up for a burst later.
+            if state.started.elapsed() > self.duration_for(state.consumed) {
+                state.started = Instant::now();
+                state.consumed = 0;
+            }
+
+            state.consumed += bytes;
+            self.duration_for(state.consumed)
+                .checked_sub(state.started.elapsed())
+        };
+
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+
+    fn duration_for(&self, bytes: u64) -> Duration {
+        Duration::from_millis(bytes.saturating_mul(1000) / self.bytes_per_second)
+    }
+}
+
+/// The bandwidth caps that apply to one transfer.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Bandwidth {
+    pub fn limited(bytes_per_second: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            limiters: bytes_per_second
+                .map(|limit| Arc::new(RateLimiter::new(limit)))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn with(&self, limiter: &Option<Arc<RateLimiter>>) -> Bandwidth {
+        let mut limiters = self.limiters.clone();
+        limiters.extend(limiter.iter().cloned());
+
+        Bandwidth { limiters: limiters }
+    }
+
+    /// Blocks until `bytes` more fit into all caps.
+    pub fn consume(&self, bytes: u64) {
+        for limiter in &self.limiters {
+            limiter.consume(bytes);
+        }
+    }
+}
+
+/// Counts the transfers that are running over all remotes.
+#[derive(Debug)]
+struct Slots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Slots {
+    fn acquire(&self) {
+        let mut free = self.free.lock().expect("slots lock is poisoned");
+        while *free == 0 {
+            free = self.released.wait(free).expect("slots lock is poisoned");
+        }
+
+        *free -= 1;
+    }
+
+    fn release(&self) {
+        *self.free.lock().expect("slots lock is poisoned") += 1;
+        self.released.notify_one();
+    }
+}
+
+/// Runs the transfers with a pool of workers per remote and returns the
+/// result of every job. Failed transfers are retried with an increasing
+/// delay before they are reported.
+pub fn run<P: AsRef<Path>>(jobs: Vec<TransferJob>, options: &SchedulerOptions, tmp_path: P) -> Vec<(TransferJob, Result<(), Error>)> {
+    if jobs.is_empty() {
+        return Vec::new();
+    }
+
+    let mut jobs = jobs;
+    match options.priority {
+        TransferPriority::SmallestFirst => jobs.sort_by_key(|job| job.len),
+        TransferPriority::LargestFirst => jobs.sort_by_key(|job| cmp::Reverse(job.len)),
+        TransferPriority::Path => jobs.sort_by(|a, b| a.destination.cmp(&b.destination)),
+    }
+
+    let mut queues: BTreeMap<String, Vec<TransferJob>> = BTreeMap::new();
+    for job in jobs {
+        queues.entry(job.remote.clone()).or_insert_with(Vec::new).push(job);
+    }
+
+    let concurrency = cmp::max(options.concurrency, 1);
+    let workers = queues
+        .iter()
+        .map(|(remote, queue)| {
+            let limit = options
+                .remotes
+                .get(remote)
+                .and_then(|limits| limits.concurrency)
+                .unwrap_or(concurrency);
+
+            cmp::max(cmp::min(cmp::min(limit, concurrency), queue.len()), 1)
+        })
+        .collect::<Vec<_>>();
+
+    let tmp_path = tmp_path.as_ref().to_path_buf();
+    let (result_tx, result_rx) = unbounded();
+    let barrier = Arc::new(Barrier::new(workers.iter().sum::<usize>() + 1));
+    let bandwidth = Bandwidth::limited(options.bandwidth_limit);
+    let slots = Arc::new(Slots {
+        free: Mutex::new(concurrency),
+        released: Condvar::new(),
+    });
+
+    for ((remote, queue), workers) in queues.into_iter().zip(workers) {
+        let (tx, rx) = unbounded();
+
+        let remote_limiter = options
+            .remotes
+            .get(&remote)
+            .and_then(|limits| limits.bandwidth_limit)
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        let bandwidth = bandwidth.with(&remote_limiter);
+
+        for worker in 0..workers {
+            let rx = rx.clone();
+            let result_tx = result_tx.clone();
+            let tmp_path = tmp_path.clone();
+            let bandwidth = bandwidth.clone();
+            let slots = Arc::clone(&slots);
+            let barrier = Arc::clone(&barrier);
+            let remote = remote.clone();
+            let retries = options.retries;
+
+            thread::spawn(move || {
+                loop {
+                    let job: TransferJob = match rx.recv() {
+                        Ok(job) => job,
+                        Err(_) => {
+                            debug!("transfer worker {} of {} has ended", worker, remote);
+                            break;
+                        }
+                    };
+
+                    let result = run_job(&job, retries, &tmp_path, &bandwidth, &slots);
+                    result_tx.send((job, result)).expect("can not send transfer result");
+                }
+
+                debug!("transfer worker thread {} of {} is waiting", worker, remote);
+                barrier.wait();
+            });
+        }
+
+        for job in queue {
+            tx.send(job).expect("can not send transfer job");
+        }
+    }
+
+    debug!("main thread is waiting for transfers");
+    barrier.wait();
+
+    let mut results = Vec::new();
+    while let Ok(result) = result_rx.try_recv() {
+        results.push(result);
+    }
+
+    results
+}
+
+fn run_job(job: &TransferJob, retries: u32, tmp_path: &Path, bandwidth: &Bandwidth, slots: &Slots) -> Result<(), Error> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+
+    loop {
+        slots.acquire();
+        let result = transfer::transfer(&job.source, &job.destination, &job.hash, tmp_path, bandwidth);
+        slots.release();
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                if attempt >= retries {
+                    return Err(err);
+                }
+
+                attempt += 1;
+                warn!(
+                    "transfer of {:?} failed, retrying in {}s ({}/{}): {}",
+                    job.destination,
+                    delay.as_secs(),
+                    attempt,
+                    retries,
+                    err
+                );
+
+                thread::sleep(delay);
+                delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_for_scales_with_the_rate() {
+        let limiter = RateLimiter::new(1000);
+
+        assert_eq!(limiter.duration_for(0), Duration::from_millis(0));
+        assert_eq!(limiter.duration_for(1), Duration::from_millis(1));
+        assert_eq!(limiter.duration_for(1000), Duration::from_secs(1));
+        assert_eq!(limiter.duration_for(2500), Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn duration_for_rounds_down() {
+        let limiter = RateLimiter::new(3000);
+
+        assert_eq!(limiter.duration_for(1), Duration::from_millis(0));
+        assert_eq!(limiter.duration_for(1000), Duration::from_millis(333));
+    }
+
+    #[test]
+    fn zero_bandwidth_limit_is_rejected() {
+        assert!(check_bandwidth_limit("bandwidth_limit", Some(0)).is_err());
+        assert!(check_bandwidth_limit("bandwidth_limit", Some(1)).is_ok());
+        assert!(check_bandwidth_limit("bandwidth_limit", None).is_ok());
+    }
+
+    #[test]
+    fn duration_for_does_not_overflow() {
+        let limiter = RateLimiter::new(1);
+
+        assert_eq!(limiter.duration_for(u64::max_value()), Duration::from_millis(u64::max_value()));
+    }
+
+    #[test]
+    fn unlimited_bandwidth_does_not_block() {
+        let bandwidth = Bandwidth::limited(None);
+        let started = Instant::now();
+
+        bandwidth.consume(u64::max_value());
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
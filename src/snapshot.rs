@@ -0,0 +1,115 @@
+use index::IndexEntry;
+use output::serialize_paths;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+/// A named point in the journal. The entries of the snapshot are the state
+/// of the index after applying the journal up to `sequence`, so creating a
+/// snapshot does not copy any entries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: SystemTime,
+    pub sequence: u64,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let created = self.created
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        write!(f, "{}\t{}\t{}", self.name, created, self.sequence)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotList {
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl fmt::Display for SnapshotList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Snapshots: {}", self.snapshots.len())?;
+
+        for snapshot in &self.snapshots {
+            write!(f, "\n{}", snapshot)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotView {
+    pub snapshot: Snapshot,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl fmt::Display for SnapshotView {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Snapshot: {}", self.snapshot)?;
+        write!(f, "\nPaths: {}", self.entries.len())?;
+
+        for entry in &self.entries {
+            write!(
+                f,
+                "\n{:?}\t{}",
+                entry.path,
+                entry.file.hash.as_ref().map(|hash| hash.as_str()).unwrap_or("-")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct SnapshotRestoreReport {
+    pub restored_count: usize,
+    pub restored_bytes: u64,
+    /// Files whose content is not present in the repository.
+    #[serde(serialize_with = "serialize_paths")]
+    pub missing_paths: BTreeSet<PathBuf>,
+    /// Files whose content could not be copied, even after retrying. The
+    /// errors are logged.
+    #[serde(serialize_with = "serialize_paths")]
+    pub failed_paths: BTreeSet<PathBuf>,
+}
+
+impl SnapshotRestoreReport {
+    pub fn is_complete(&self) -> bool {
+        self.missing_paths.is_empty() && self.failed_paths.is_empty()
+    }
+}
+
+impl fmt::Display for SnapshotRestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Restored Paths: {}", self.restored_count)?;
+        write!(f, "\nRestored Bytes: {}", self.restored_bytes)?;
+
+        if !self.missing_paths.is_empty() {
+            let paths = self.missing_paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nMissing Content:\n{}", paths)?;
+        }
+
+        if !self.failed_paths.is_empty() {
+            let paths = self.failed_paths
+                .iter()
+                .fold(String::new(), |acc, x| format!("{}\t{:?}\n", acc, x));
+
+            write!(f, "\nFailed Transfers:\n{}", paths)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use failure::{
+    Error,
+    ResultExt,
+};
+use libc;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::cmp;
+use std::fmt::Debug;
+use std::fs::{
+    copy,
+    File,
+    Metadata,
+};
+use std::io;
+use std::io::{
+    Read,
+    Seek,
+    SeekFrom,
+};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Size of the blocks `st_blocks` counts in.
+const BLOCK_SIZE: u64 = 512;
+
+/// Zeros fed to the hash for holes at a time.
+const ZERO_CHUNK_SIZE: usize = 64 * 1024;
+
+static ZEROS: [u8; ZERO_CHUNK_SIZE] = [0; ZERO_CHUNK_SIZE];
+
+/// Bytes actually allocated on disk for the file.
+pub fn allocated_bytes(metadata: &Metadata) -> u64 {
+    metadata.blocks() * BLOCK_SIZE
+}
+
+/// A file is treated as sparse when it has fewer blocks allocated than its
+/// apparent size needs.
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    metadata.is_file() && allocated_bytes(metadata) < metadata.len()
+}
+
+/// Returns the regions of the file that contain data as offset and length.
+/// Everything else is a hole that reads as zeros. Filesystems without
+/// SEEK_DATA support report the whole file as one region.
+pub fn data_regions(file: &File, len: u64) -> Result<Vec<(u64, u64)>, Error> {
+    let fd = file.as_raw_fd();
+    let mut out = Vec::new();
+    let mut offset = 0;
+
+    while offset < len {
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // No data after the offset, the rest of the file is a hole.
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) => return Ok(vec![(0, len)]),
+                _ => Err(err).context("can not seek to next data region")?,
+            }
+        }
+
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            Err(io::Error::last_os_error()).context("can not seek to next hole")?
+        }
+
+        let end = cmp::min(hole as u64, len);
+        out.push((data as u64, end - data as u64));
+        offset = end;
+    }
+
+    Ok(out)
+}
+
+/// Hashes the file like reading it from start to end would but only reads
+/// the data regions. Holes are fed to the hash as zeros.
+pub fn hash_regions(file: &mut File, len: u64, regions: &[(u64, u64)]) -> Result<String, Error> {
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0u8; ZERO_CHUNK_SIZE];
+    let mut position = 0;
+
+    for &(start, length) in regions {
+        input_zeros(&mut hasher, start - position);
+
+        file.seek(SeekFrom::Start(start))?;
+        let mut remaining = length;
+        while remaining > 0 {
+            let chunk = cmp::min(remaining, buffer.len() as u64) as usize;
+            file.read_exact(&mut buffer[..chunk])?;
+            hasher.input(&buffer[..chunk]);
+            remaining -= chunk as u64;
+        }
+
+        position = start + length;
+    }
+
+    input_zeros(&mut hasher, len - position);
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Copies a file and keeps the holes of sparse files instead of writing
+/// them out as zeros.
+pub fn copy_file<P: AsRef<Path> + Debug, Q: AsRef<Path> + Debug>(source: P, destination: Q) -> Result<(), Error> {
+    let mut source_file = File::open(&source).context(format_err!("can not open {:?}", source))?;
+    let metadata = source_file
+        .metadata()
+        .context(format_err!("can not get metadata for file {:?}", source))?;
+
+    if !is_sparse(&metadata) {
+        copy(&source, &destination).context(format_err!("can not copy {:?} to {:?}", source, destination))?;
+        return Ok(());
+    }
+
+    let regions = data_regions(&source_file, metadata.len())?;
+    let mut destination_file = File::create(&destination).context(format_err!("can not create {:?}", destination))?;
+
+    for (start, length) in regions {
+        source_file.seek(SeekFrom::Start(start))?;
+        destination_file.seek(SeekFrom::Start(start))?;
+
+        io::copy(&mut (&mut source_file).take(length), &mut destination_file)
+            .context(format_err!("can not copy {:?} to {:?}", source, destination))?;
+    }
+
+    // Trailing holes are not written so the length has to be set explicitly.
+    destination_file.set_len(metadata.len())?;
+
+    Ok(())
+}
+
+/// Feeds `count` zeros to the hash, which is what a hole reads as.
+pub fn input_zeros(hasher: &mut Sha256, mut count: u64) {
+    while count > 0 {
+        let chunk = cmp::min(count, ZERO_CHUNK_SIZE as u64) as usize;
+        hasher.input(&ZEROS[..chunk]);
+        count -= chunk as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use testdir::TestDir;
+
+    /// Writes `data` at the given offsets and leaves holes everywhere else.
+    fn write_sparse(path: &Path, len: u64, data: &[(u64, &[u8])]) {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        for &(offset, bytes) in data {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(bytes).unwrap();
+        }
+
+        file.set_len(len).unwrap();
+    }
+
+    fn plain_hash(path: &Path) -> String {
+        format!("{:x}", Sha256::digest_reader(&mut File::open(path).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn hash_regions_equals_plain_hash() {
+        let dir = TestDir::new("sparse-hash");
+        let path = dir.join("file");
+        let len = 4 * 1024 * 1024;
+        write_sparse(&path, len, &[(0, b"start"), (1024 * 1024 + 7, b"middle"), (3 * 1024 * 1024, b"end")]);
+
+        let mut file = File::open(&path).unwrap();
+        let regions = data_regions(&file, len).unwrap();
+        let hash = hash_regions(&mut file, len, &regions).unwrap();
+
+        assert_eq!(hash, plain_hash(&path));
+    }
+
+    #[test]
+    fn hash_regions_with_explicit_holes() {
+        let dir = TestDir::new("sparse-explicit");
+        let path = dir.join("file");
+        let mut content = vec![0u8; 300 * 1024];
+        content[..3].copy_from_slice(b"abc");
+        content[200 * 1024..200 * 1024 + 3].copy_from_slice(b"xyz");
+        File::create(&path).unwrap().write_all(&content).unwrap();
+
+        // Regions only have to cover the non zero bytes, everything else
+        // hashes as zeros whether it is a hole or not.
+        let mut file = File::open(&path).unwrap();
+        let len = content.len() as u64;
+        let expected = format!("{:x}", Sha256::digest(&content));
+
+        assert_eq!(hash_regions(&mut file, len, &[(0, 3), (200 * 1024, 3)]).unwrap(), expected);
+        assert_eq!(hash_regions(&mut file, len, &[(0, len)]).unwrap(), expected);
+        assert_eq!(plain_hash(&path), expected);
+    }
+
+    #[test]
+    fn hash_regions_of_empty_and_all_hole_files() {
+        let dir = TestDir::new("sparse-holes");
+        let path = dir.join("file");
+        write_sparse(&path, 1024 * 1024, &[]);
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(hash_regions(&mut file, 1024 * 1024, &[]).unwrap(), plain_hash(&path));
+        assert_eq!(hash_regions(&mut file, 0, &[]).unwrap(), format!("{:x}", Sha256::digest(b"")));
+    }
+
+    #[test]
+    fn copy_file_keeps_content() {
+        let dir = TestDir::new("sparse-copy");
+        let source = dir.join("source");
+        let destination = dir.join("destination");
+        write_sparse(&source, 2 * 1024 * 1024, &[(10, b"data"), (1024 * 1024, b"more")]);
+
+        copy_file(&source, &destination).unwrap();
+
+        assert_eq!(plain_hash(&source), plain_hash(&destination));
+    }
+}
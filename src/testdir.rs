@@ -0,0 +1,42 @@
+use std::env;
+use std::fs::{
+    create_dir_all,
+    remove_dir_all,
+};
+use std::ops::Deref;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process;
+
+/// An empty directory below the system temporary directory for a test. It
+/// is removed when dropped, so a failing test does not leave it behind.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Creates the directory, `name` has to be unique among all tests.
+    pub fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!("syncust-{}-{}", process::id(), name));
+        let _ = remove_dir_all(&path);
+        create_dir_all(&path).unwrap();
+
+        TestDir { path: path }
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.path);
+    }
+}
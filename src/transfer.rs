@@ -0,0 +1,502 @@
+use failure::{
+    Error,
+    ResultExt,
+};
+use libc;
+use scheduler::Bandwidth;
+use serde_json::{
+    from_reader,
+    to_writer,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use sparse;
+use std::cmp;
+use std::fmt::Debug;
+use std::fs::{
+    create_dir_all,
+    metadata,
+    remove_file,
+    rename,
+    File,
+    OpenOptions,
+};
+use std::io;
+use std::io::{
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+/// Bytes copied at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Bytes copied between two transfer records. Everything before the offset
+/// in the record is synced to disk.
+const RECORD_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// Progress of a transfer that was interrupted, stored next to the partial
+/// file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferRecord {
+    pub hash: String,
+    pub len: u64,
+    /// Bytes of the partial file that were written and synced.
+    pub offset: u64,
+    /// Hash of the first `offset` bytes, used to verify the partial file
+    /// before resuming.
+    pub offset_hash: String,
+}
+
+/// Copies content into the working tree without ever leaving a half written
+/// file there. The content is written to a partial file in `tmp_path` named
+/// after its hash and destination, verified against `hash` and renamed into
+/// place. An interrupted transfer to the same destination continues from the
+/// last recorded offset after checking that the partial file still has the
+/// recorded content. Transfers to the same destination wait for each other,
+/// transfers of the same content to different destinations do not share
+/// anything. Holes of sparse sources are kept without reading them. Reading
+/// the source is throttled to `bandwidth`.
+pub fn transfer<P: AsRef<Path> + Debug, Q: AsRef<Path> + Debug, T: AsRef<Path>>(
+    source: P,
+    destination: Q,
+    hash: &str,
+    tmp_path: T,
+    bandwidth: &Bandwidth,
+) -> Result<(), Error> {
+    let tmp_path = tmp_path.as_ref();
+    create_dir_all(tmp_path).context(format_err!("can not create transfer directory {:?}", tmp_path))?;
+
+    let name = transfer_name(hash, destination.as_ref());
+    let partial_path = tmp_path.join(format!("{}.partial", name));
+    let record_path = tmp_path.join(format!("{}.json", name));
+    let _lock = TransferLock::acquire(&tmp_path.join(format!("{}.lock", name)))?;
+
+    let mut source_file = File::open(&source).context(format_err!("can not open {:?}", source))?;
+    let metadata = source_file
+        .metadata()
+        .context(format_err!("can not get metadata for file {:?}", source))?;
+    let len = metadata.len();
+    let sparse = sparse::is_sparse(&metadata);
+
+    let (offset, mut hasher) = resume_point(&partial_path, &record_path, hash, len)?;
+    if offset > 0 {
+        info!("resuming transfer of {:?} at byte {} of {}", source, offset, len);
+    }
+
+    let mut partial = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(&partial_path)
+        .context(format_err!("can not open partial file {:?}", partial_path))?;
+
+    // Anything after the offset was written after the last record and is
+    // not trusted.
+    partial.set_len(offset)?;
+
+    // Only the data regions of sparse sources are read and written, the
+    // holes are skipped on both ends and do not count against the bandwidth.
+    let regions = if sparse {
+        sparse::data_regions(&source_file, len)?
+    } else {
+        vec![(0, len)]
+    };
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut position = offset;
+    let mut last_record = offset;
+
+    for (start, length) in regions {
+        let end = start + length;
+        if end <= position {
+            continue;
+        }
+
+        let start = cmp::max(start, position);
+        sparse::input_zeros(&mut hasher, start - position);
+        position = start;
+
+        source_file.seek(SeekFrom::Start(position))?;
+        partial.seek(SeekFrom::Start(position))?;
+
+        while position < end {
+            let chunk = cmp::min(end - position, CHUNK_SIZE as u64) as usize;
+            bandwidth.consume(chunk as u64);
+            source_file
+                .read_exact(&mut buffer[..chunk])
+                .context(format_err!("can not read {:?}", source))?;
+            hasher.input(&buffer[..chunk]);
+            partial
+                .write_all(&buffer[..chunk])
+                .context(format_err!("can not write partial file {:?}", partial_path))?;
+
+            position += chunk as u64;
+
+            if position - last_record >= RECORD_INTERVAL && position < len {
+                partial.sync_data()?;
+
+                write_record(
+                    &record_path,
+                    &TransferRecord {
+                        hash: hash.to_string(),
+                        len: len,
+                        offset: position,
+                        offset_hash: format!("{:x}", hasher.clone().result()),
+                    },
+                )?;
+
+                last_record = position;
+            }
+        }
+    }
+
+    sparse::input_zeros(&mut hasher, len - position);
+
+    partial.set_len(len)?;
+    partial.sync_all()?;
+    drop(partial);
+
+    let transferred_hash = format!("{:x}", hasher.result());
+    if transferred_hash != hash {
+        remove_transfer(&partial_path, &record_path)?;
+        bail!(
+            "content of {:?} has hash {} instead of {}, the source changed during the transfer",
+            source,
+            transferred_hash,
+            hash
+        )
+    }
+
+    move_into_place(&partial_path, destination.as_ref())?;
+    remove_transfer(&partial_path, &record_path)?;
+
+    Ok(())
+}
+
+/// Names the files of a transfer after the content and a hash of the
+/// destination, the hash alone would make transfers of the same content to
+/// different destinations overwrite each other's partial file.
+fn transfer_name(hash: &str, destination: &Path) -> String {
+    let destination_hash = format!("{:x}", Sha256::digest(destination.as_os_str().as_bytes()));
+
+    format!("{}-{}", hash, &destination_hash[..16])
+}
+
+/// Exclusive lock of the files of a transfer, released and removed when
+/// dropped.
+struct TransferLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl TransferLock {
+    fn acquire(path: &Path) -> Result<TransferLock, Error> {
+        loop {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(path)
+                .context(format_err!("can not open transfer lock {:?}", path))?;
+
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                Err(io::Error::last_os_error()).context(format_err!("can not lock {:?}", path))?
+            }
+
+            // The previous holder removes the lock file before releasing it,
+            // a lock on the removed file does not keep others out.
+            let locked = file.metadata()?.ino();
+            match metadata(path) {
+                Ok(ref current) if current.ino() == locked => {
+                    return Ok(TransferLock {
+                        path: path.to_path_buf(),
+                        _file: file,
+                    })
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Drop for TransferLock {
+    fn drop(&mut self) {
+        if let Err(err) = remove_file(&self.path) {
+            warn!("can not remove transfer lock {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// Returns the offset to continue an interrupted transfer at and the hash
+/// state up to it. Starts over when there is no usable record or the
+/// partial file does not match it.
+fn resume_point(partial_path: &Path, record_path: &Path, hash: &str, len: u64) -> Result<(u64, Sha256), Error> {
+    let start = (0, Sha256::default());
+
+    if !record_path.exists() || !partial_path.exists() {
+        return Ok(start);
+    }
+
+    let record: TransferRecord = match File::open(record_path).map_err(Error::from).and_then(|file| Ok(from_reader(file)?)) {
+        Ok(record) => record,
+        Err(err) => {
+            warn!("ignoring unreadable transfer record {:?}: {}", record_path, err);
+            return Ok(start);
+        }
+    };
+
+    if record.hash != hash || record.len != len {
+        return Ok(start);
+    }
+
+    let mut partial = File::open(partial_path).context(format_err!("can not open partial file {:?}", partial_path))?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut remaining = record.offset;
+
+    while remaining > 0 {
+        let chunk = cmp::min(remaining, CHUNK_SIZE as u64) as usize;
+        if partial.read_exact(&mut buffer[..chunk]).is_err() {
+            warn!("partial file {:?} is shorter than its record, starting over", partial_path);
+            return Ok(start);
+        }
+
+        hasher.input(&buffer[..chunk]);
+        remaining -= chunk as u64;
+    }
+
+    if format!("{:x}", hasher.clone().result()) != record.offset_hash {
+        warn!("partial file {:?} does not match its record, starting over", partial_path);
+        return Ok(start);
+    }
+
+    Ok((record.offset, hasher))
+}
+
+fn write_record(record_path: &Path, record: &TransferRecord) -> Result<(), Error> {
+    // Written next to the record and renamed so an interruption never
+    // leaves a torn record behind.
+    let new_path = record_path.with_extension("json.new");
+
+    let file = File::create(&new_path).context(format_err!("can not create transfer record {:?}", new_path))?;
+    to_writer(&file, record).context(format_err!("can not write transfer record {:?}", new_path))?;
+    file.sync_all()?;
+
+    rename(&new_path, record_path).context(format_err!("can not replace transfer record {:?}", record_path))?;
+
+    Ok(())
+}
+
+/// Renames the verified partial file to the destination. Destinations on
+/// another filesystem get a copy next to them first so the final step is
+/// still a rename.
+fn move_into_place(partial_path: &Path, destination: &Path) -> Result<(), Error> {
+    let err = match rename(partial_path, destination) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+
+    if err.raw_os_error() != Some(libc::EXDEV) {
+        Err(err).context(format_err!("can not move {:?} to {:?}", partial_path, destination))?
+    }
+
+    let staging_path = staging_path(destination);
+    sparse::copy_file(partial_path, &staging_path)?;
+    File::open(&staging_path)?.sync_all()?;
+
+    rename(&staging_path, destination).context(format_err!("can not move {:?} to {:?}", staging_path, destination))?;
+
+    Ok(())
+}
+
+fn staging_path(destination: &Path) -> PathBuf {
+    let name = destination
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    destination.with_file_name(format!(".{}.syncust-partial", name))
+}
+
+fn remove_transfer(partial_path: &Path, record_path: &Path) -> Result<(), Error> {
+    for path in &[partial_path, record_path] {
+        if path.exists() {
+            remove_file(path).context(format_err!("can not remove transfer file {:?}", path))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{
+        read,
+        write,
+    };
+    use std::time::Instant;
+    use testdir::TestDir;
+
+    const CONTENT: &[u8] = b"the content of the transferred file";
+
+    fn hash(bytes: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(bytes))
+    }
+
+    /// Leaves a partial file with the first `offset` bytes of `CONTENT` and
+    /// a record for them.
+    fn interrupted(dir: &Path, offset: usize) -> (PathBuf, PathBuf) {
+        let partial_path = dir.join("partial");
+        let record_path = dir.join("record.json");
+
+        write(&partial_path, &CONTENT[..offset]).unwrap();
+        write_record(
+            &record_path,
+            &TransferRecord {
+                hash: hash(CONTENT),
+                len: CONTENT.len() as u64,
+                offset: offset as u64,
+                offset_hash: hash(&CONTENT[..offset]),
+            },
+        ).unwrap();
+
+        (partial_path, record_path)
+    }
+
+    fn offset(partial_path: &Path, record_path: &Path, hash: &str, len: u64) -> u64 {
+        resume_point(partial_path, record_path, hash, len).unwrap().0
+    }
+
+    #[test]
+    fn resumes_at_recorded_offset() {
+        let dir = TestDir::new("transfer-resume");
+        let (partial_path, record_path) = interrupted(&dir, 10);
+
+        let (offset, hasher) = resume_point(&partial_path, &record_path, &hash(CONTENT), CONTENT.len() as u64).unwrap();
+
+        assert_eq!(offset, 10);
+        assert_eq!(format!("{:x}", hasher.result()), hash(&CONTENT[..10]));
+    }
+
+    #[test]
+    fn ignores_data_written_after_the_record() {
+        let dir = TestDir::new("transfer-longer");
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        write(&partial_path, &CONTENT[..20]).unwrap();
+
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), CONTENT.len() as u64), 10);
+    }
+
+    #[test]
+    fn starts_over_without_record_or_partial() {
+        let dir = TestDir::new("transfer-missing");
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        let len = CONTENT.len() as u64;
+
+        remove_file(&record_path).unwrap();
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), len), 0);
+
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        remove_file(&partial_path).unwrap();
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), len), 0);
+    }
+
+    #[test]
+    fn starts_over_for_other_content() {
+        let dir = TestDir::new("transfer-other");
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        let len = CONTENT.len() as u64;
+
+        assert_eq!(offset(&partial_path, &record_path, &hash(b"other"), len), 0);
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), len + 1), 0);
+    }
+
+    #[test]
+    fn starts_over_for_damaged_partial() {
+        let dir = TestDir::new("transfer-damaged");
+        let len = CONTENT.len() as u64;
+
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        write(&partial_path, &CONTENT[..5]).unwrap();
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), len), 0);
+
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        write(&partial_path, b"0123456789").unwrap();
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), len), 0);
+    }
+
+    #[test]
+    fn starts_over_for_unreadable_record() {
+        let dir = TestDir::new("transfer-unreadable");
+        let (partial_path, record_path) = interrupted(&dir, 10);
+        write(&record_path, b"{ not json").unwrap();
+
+        assert_eq!(offset(&partial_path, &record_path, &hash(CONTENT), CONTENT.len() as u64), 0);
+    }
+
+    #[test]
+    fn transfer_moves_verified_content_into_place() {
+        let dir = TestDir::new("transfer-into-place");
+        let source = dir.join("source");
+        let destination = dir.join("destination");
+        let tmp_path = dir.join("tmp");
+        write(&source, CONTENT).unwrap();
+
+        transfer(&source, &destination, &hash(CONTENT), &tmp_path, &Bandwidth::default()).unwrap();
+
+        assert_eq!(read(&destination).unwrap(), CONTENT);
+        assert_eq!(tmp_path.read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn transfer_rejects_content_with_other_hash() {
+        let dir = TestDir::new("transfer-mismatch");
+        let source = dir.join("source");
+        let destination = dir.join("destination");
+        write(&source, CONTENT).unwrap();
+
+        assert!(transfer(&source, &destination, &hash(b"other"), dir.join("tmp"), &Bandwidth::default()).is_err());
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn transfer_skips_holes_of_sparse_sources() {
+        let dir = TestDir::new("transfer-sparse");
+        let source = dir.join("source");
+        let destination = dir.join("destination");
+        let len = 16 * 1024 * 1024;
+
+        let mut file = File::create(&source).unwrap();
+        file.write_all(b"start").unwrap();
+        file.seek(SeekFrom::Start(len - 3)).unwrap();
+        file.write_all(b"end").unwrap();
+        drop(file);
+
+        let content = read(&source).unwrap();
+        let sparse = sparse::data_regions(&File::open(&source).unwrap(), len)
+            .unwrap()
+            .iter()
+            .map(|&(_, length)| length)
+            .sum::<u64>() < len;
+
+        let started = Instant::now();
+        transfer(&source, &destination, &hash(&content), dir.join("tmp"), &Bandwidth::limited(Some(256 * 1024))).unwrap();
+
+        assert_eq!(read(&destination).unwrap(), content);
+
+        // Reading the holes at 256 KiB/s would take a minute.
+        if sparse {
+            assert!(started.elapsed().as_secs() < 30);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{
+    Deserializer,
+    Serializer,
+};
+use serde::de::{
+    Error,
+    Visitor,
+};
+use std::fmt;
+use uuid::Uuid;
+
+/// Stores UUIDs as their 16 raw bytes. The serde support of the uuid crate
+/// writes the hyphenated string, which more than doubles the size of every
+/// index entry and journal record. Use with `#[serde(with = "uuidbytes")]`.
+pub fn serialize<S: Serializer>(uuid: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(uuid.as_bytes())
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+    deserializer.deserialize_bytes(UuidVisitor)
+}
+
+struct UuidVisitor;
+
+impl<'de> Visitor<'de> for UuidVisitor {
+    type Value = Uuid;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the 16 bytes of a uuid")
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Uuid, E> {
+        Uuid::from_bytes(bytes).map_err(|err| E::custom(format!("invalid uuid: {}", err)))
+    }
+}